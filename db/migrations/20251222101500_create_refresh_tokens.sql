@@ -0,0 +1,18 @@
+-- Refresh tokens (only a hash of the token is stored)
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  token_hash VARCHAR(64) NOT NULL UNIQUE, -- base64url(sha256(token))
+  family_id CHAR(36) NOT NULL, -- shared by every token rotated from the same grant
+  client_id_ref BIGINT UNSIGNED NOT NULL,
+  user_id VARCHAR(255) NOT NULL,
+  scope TEXT NOT NULL, -- space-delimited
+  expires_at TIMESTAMP NOT NULL,
+  rotated_at TIMESTAMP NULL DEFAULT NULL,
+  revoked_at TIMESTAMP NULL DEFAULT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (id),
+  CONSTRAINT fk_refresh_tokens_client
+    FOREIGN KEY (client_id_ref) REFERENCES clients(id)
+    ON DELETE CASCADE ON UPDATE CASCADE,
+  INDEX idx_refresh_tokens_family (family_id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci;
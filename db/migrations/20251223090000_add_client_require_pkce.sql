@@ -0,0 +1,3 @@
+-- Public clients (SPAs, mobile apps) should be registered with PKCE required
+ALTER TABLE clients
+  ADD COLUMN require_pkce BOOLEAN NOT NULL DEFAULT FALSE AFTER client_secret_hash;
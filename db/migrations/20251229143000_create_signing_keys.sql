@@ -0,0 +1,12 @@
+-- Server-owned keys used to sign access tokens
+CREATE TABLE IF NOT EXISTS signing_keys (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  kid VARCHAR(64) NOT NULL UNIQUE,
+  alg VARCHAR(16) NOT NULL, -- RS256, ES256 or EdDSA
+  private_key BLOB NOT NULL, -- DER: PKCS#1 for RSA, PKCS#8 for EC / Ed25519
+  public_jwk TEXT NOT NULL, -- JSON, published as-is
+  status VARCHAR(16) NOT NULL DEFAULT 'active',
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (id),
+  INDEX idx_signing_keys_status (status)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci;
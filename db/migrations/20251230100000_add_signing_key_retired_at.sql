@@ -0,0 +1,3 @@
+-- Retired keys stay published in JWKS until the tokens they signed have expired
+ALTER TABLE signing_keys
+  ADD COLUMN retired_at TIMESTAMP NULL DEFAULT NULL AFTER status;
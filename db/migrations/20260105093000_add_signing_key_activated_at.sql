@@ -0,0 +1,5 @@
+-- Keys now go pending -> active -> retired, activated_at drives the rotation schedule
+ALTER TABLE signing_keys
+  ADD COLUMN activated_at TIMESTAMP NULL DEFAULT NULL AFTER status;
+
+UPDATE signing_keys SET activated_at = created_at WHERE status = 'active';
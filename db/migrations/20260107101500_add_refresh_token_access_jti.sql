@@ -0,0 +1,4 @@
+-- jti of the access token issued together with each refresh token,
+-- so revoking a family can also deny-list the access tokens derived from it
+ALTER TABLE refresh_tokens
+  ADD COLUMN access_token_jti CHAR(36) NULL DEFAULT NULL AFTER scope;
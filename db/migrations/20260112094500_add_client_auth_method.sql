@@ -0,0 +1,6 @@
+-- How a client authenticates at /token, /introspect and /revoke.
+-- private_key_jwt clients register their public keys inline (jwks) or by URL (jwks_uri).
+ALTER TABLE clients
+  ADD COLUMN token_endpoint_auth_method VARCHAR(32) NOT NULL DEFAULT 'client_secret_basic' AFTER require_pkce,
+  ADD COLUMN jwks TEXT NULL DEFAULT NULL AFTER token_endpoint_auth_method,
+  ADD COLUMN jwks_uri VARCHAR(2048) NULL DEFAULT NULL AFTER jwks;
@@ -0,0 +1,4 @@
+-- tls_client_auth clients (RFC 8705) register the subject DN of their certificate.
+-- self_signed_tls_client_auth clients register the certificate itself (x5c) in jwks / jwks_uri.
+ALTER TABLE clients
+  ADD COLUMN tls_client_auth_subject_dn VARCHAR(1024) NULL DEFAULT NULL AFTER jwks_uri;
@@ -0,0 +1,3 @@
+-- JWK thumbprint of the DPoP key a refresh token family is bound to (RFC 9449 section 5)
+ALTER TABLE refresh_tokens
+  ADD COLUMN dpop_jkt VARCHAR(64) NULL DEFAULT NULL AFTER access_token_jti;
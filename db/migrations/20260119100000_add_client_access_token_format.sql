@@ -0,0 +1,4 @@
+-- jwt: self-contained signed access tokens
+-- opaque: random handles resolved through /introspect, the claims live in redis
+ALTER TABLE clients
+  ADD COLUMN access_token_format VARCHAR(16) NOT NULL DEFAULT 'jwt' AFTER tls_client_auth_subject_dn;
@@ -0,0 +1,7 @@
+-- Per-client lifetimes in seconds, NULL means the server default applies
+ALTER TABLE clients
+  ADD COLUMN access_token_lifetime_secs INT UNSIGNED NULL DEFAULT NULL AFTER access_token_format,
+  ADD COLUMN refresh_token_idle_lifetime_secs INT UNSIGNED NULL DEFAULT NULL AFTER access_token_lifetime_secs,
+  ADD COLUMN refresh_token_absolute_lifetime_secs INT UNSIGNED NULL DEFAULT NULL AFTER refresh_token_idle_lifetime_secs,
+  ADD COLUMN auth_code_lifetime_secs INT UNSIGNED NULL DEFAULT NULL AFTER refresh_token_absolute_lifetime_secs,
+  ADD COLUMN session_lifetime_secs INT UNSIGNED NULL DEFAULT NULL AFTER auth_code_lifetime_secs;
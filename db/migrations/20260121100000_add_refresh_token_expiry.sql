@@ -0,0 +1,11 @@
+-- No rotation extends a token family past family_expires_at (absolute lifetime)
+-- access_token_expires_at: when the access token issued with the refresh token expires, see revoke_refresh_family
+ALTER TABLE refresh_tokens
+  ADD COLUMN family_expires_at TIMESTAMP NULL DEFAULT NULL AFTER expires_at,
+  ADD COLUMN access_token_expires_at TIMESTAMP NULL DEFAULT NULL AFTER access_token_jti;
+
+-- existing tokens: families end with their current token, access tokens were issued for an hour
+UPDATE refresh_tokens SET family_expires_at = expires_at;
+UPDATE refresh_tokens
+  SET access_token_expires_at = DATE_ADD(created_at, INTERVAL 1 HOUR)
+  WHERE access_token_jti IS NOT NULL;
@@ -0,0 +1,21 @@
+-- Protected resources (APIs), the audience of tokens requested with a resource indicator (RFC 8707)
+CREATE TABLE IF NOT EXISTS resources (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  identifier VARCHAR(255) NOT NULL UNIQUE, -- absolute URI, becomes the token's aud
+  name VARCHAR(255) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci;
+
+-- Scopes the resource understands, tokens for it carry no others
+CREATE TABLE IF NOT EXISTS resource_scopes (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  resource_id_ref BIGINT UNSIGNED NOT NULL,
+  scope VARCHAR(128) NOT NULL,
+  PRIMARY KEY (id),
+  CONSTRAINT fk_resource_scopes_resource
+    FOREIGN KEY (resource_id_ref) REFERENCES resources(id)
+    ON DELETE CASCADE ON UPDATE CASCADE,
+  UNIQUE KEY uniq_resource_scope (resource_id_ref, scope),
+  INDEX idx_resource_scopes_resource (resource_id_ref)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_unicode_ci;
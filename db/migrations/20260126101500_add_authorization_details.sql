@@ -0,0 +1,8 @@
+-- Rich Authorization Requests (RFC 9396)
+-- clients: JSON object mapping each authorization details type the client uses to a JSON Schema
+-- refresh_tokens: the authorization_details the user approved, carried into refreshed access tokens
+ALTER TABLE clients
+  ADD COLUMN authorization_details_types TEXT NULL DEFAULT NULL AFTER session_lifetime_secs;
+
+ALTER TABLE refresh_tokens
+  ADD COLUMN authorization_details TEXT NULL DEFAULT NULL AFTER scope;
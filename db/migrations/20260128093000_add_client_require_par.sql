@@ -0,0 +1,3 @@
+-- Pushed Authorization Requests (RFC 9126): clients that may only start authorization through /par
+ALTER TABLE clients
+  ADD COLUMN require_pushed_authorization_requests BOOLEAN NOT NULL DEFAULT FALSE AFTER require_pkce;
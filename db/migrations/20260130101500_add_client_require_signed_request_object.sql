@@ -0,0 +1,3 @@
+-- JWT-secured authorization requests (RFC 9101): clients whose authorization requests must come as a signed request object
+ALTER TABLE clients
+  ADD COLUMN require_signed_request_object BOOLEAN NOT NULL DEFAULT FALSE AFTER require_pushed_authorization_requests;
@@ -0,0 +1,3 @@
+-- Response modes a client may ask for at /authorize, space-separated (query, fragment, form_post, web_message)
+ALTER TABLE clients
+  ADD COLUMN response_modes VARCHAR(255) NOT NULL DEFAULT 'query' AFTER authorization_details_types;
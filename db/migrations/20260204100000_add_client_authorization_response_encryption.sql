@@ -0,0 +1,4 @@
+-- JWT Secured Authorization Response Mode (JARM): responses are encrypted to the client's key when these are set
+ALTER TABLE clients
+  ADD COLUMN authorization_encrypted_response_alg VARCHAR(32) NULL DEFAULT NULL AFTER response_modes,
+  ADD COLUMN authorization_encrypted_response_enc VARCHAR(32) NULL DEFAULT NULL AFTER authorization_encrypted_response_alg;
@@ -0,0 +1,7 @@
+-- private_key used to be stored as plain DER: anyone who can read this table (a replica, a backup,
+-- a leaked dump) can sign tokens that every resource server accepts.
+-- With SIGNING_KEY_ENCRYPTION_KEY set, new keys are stored AES-256-GCM encrypted (nonce || ciphertext || tag,
+-- the kid as associated data) and the table alone is no longer enough; the encryption key then has
+-- to be kept outside the database. Existing rows stay plain DER until rotation replaces them.
+ALTER TABLE signing_keys
+  ADD COLUMN private_key_encrypted BOOLEAN NOT NULL DEFAULT FALSE AFTER private_key;
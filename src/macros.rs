@@ -42,3 +42,57 @@ macro_rules! redis_get {
         val
     }};
 }
+
+/*
+ * SET NX EX variant (only sets the key if it does not exist yet):
+ * Usage: let fresh: bool = redis_set_nx_ex!(conn, "device_poll", code, "1", interval);
+ */
+
+#[macro_export]
+macro_rules! redis_set_nx_ex {
+    ($conn:expr, $prefix:expr, $key:expr, $value:expr, $ttl:expr) => {{
+        let full_key = format!("{}:{}", $prefix, $key);
+        let set: Option<String> = redis::cmd("SET")
+            .arg(full_key)
+            .arg($value)
+            .arg("NX")
+            .arg("EX")
+            .arg($ttl)
+            .query_async(&mut $conn)
+            .await?;
+        set.is_some()
+    }};
+}
+
+/*
+ * SET KEEPTTL variant (overwrites the value, keeps the remaining expiry):
+ * Usage: redis_set_keepttl!(conn, "device_code", code, payload);
+ */
+
+#[macro_export]
+macro_rules! redis_set_keepttl {
+    ($conn:expr, $prefix:expr, $key:expr, $value:expr) => {{
+        let full_key = format!("{}:{}", $prefix, $key);
+        let _: () = redis::cmd("SET")
+            .arg(full_key)
+            .arg($value)
+            .arg("KEEPTTL")
+            .query_async(&mut $conn)
+            .await?;
+    }};
+}
+
+/*
+ * INCR variant:
+ * Usage: let n: u64 = redis_incr!(conn, "signing_keys", "generation");
+ */
+
+#[macro_export]
+macro_rules! redis_incr {
+    ($conn:expr, $prefix:expr, $key:expr) => {{
+        use redis::AsyncCommands;
+        let full_key = format!("{}:{}", $prefix, $key);
+        let val = $conn.incr(full_key, 1).await?;
+        val
+    }};
+}
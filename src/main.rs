@@ -5,6 +5,7 @@ mod repositories;
 mod routes;
 mod services;
 mod state;
+mod tls;
 
 use axum::middleware as _middleware;
 use std::net::SocketAddr;
@@ -15,8 +16,12 @@ use tracing_subscriber::EnvFilter;
 
 use crate::middleware::log_mw;
 use crate::routes::routes;
+use crate::services::keys::{
+    init_signing_keys, rotate_signing_keys, spawn_key_rotation, KeyRotation,
+};
 
 use crate::state::AppState;
+use crate::tls::{serve_tls, server_config};
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 8)]
 async fn main() {
@@ -34,6 +39,21 @@ async fn main() {
     // End tracing subscriber setup
 
     let appstate = AppState::new_with_db(2 * 1024 * 1024, 1024).await.unwrap();
+    init_signing_keys(&appstate).await.unwrap();
+
+    // admin command: `loom rotate-keys` publishes a new pending key right away,
+    // the running servers activate it once it has been in JWKS long enough
+    if std::env::args().nth(1).as_deref() == Some("rotate-keys") {
+        match rotate_signing_keys(&appstate, true).await.unwrap() {
+            KeyRotation::Busy => println!("Another instance is rotating keys, try again shortly"),
+            KeyRotation::Unchanged => println!("A pending key is already scheduled"),
+            KeyRotation::Scheduled(kid) => println!("Published pending signing key {kid}"),
+            KeyRotation::Activated(kid) => println!("Activated signing key {kid}"),
+        }
+        return;
+    }
+
+    spawn_key_rotation(appstate.clone());
 
     let addr: SocketAddr = "0.0.0.0:3000".parse().unwrap();
 
@@ -46,11 +66,23 @@ async fn main() {
         .with_state(appstate)
         .layer(_middleware::from_fn(log_mw));
 
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+
+    // terminate TLS ourselves when configured, needed for mTLS client authentication
+    if let (Ok(cert_path), Ok(key_path)) = (
+        std::env::var("TLS_CERT_PATH"),
+        std::env::var("TLS_KEY_PATH"),
+    ) {
+        let config = server_config(&cert_path, &key_path).unwrap();
+        info!("Server started on https://{addr}");
+        serve_tls(listener, router, config).await;
+        return;
+    }
+
     // Convert with connect info AFTER layers are set
     let service = router.into_make_service_with_connect_info::<SocketAddr>();
 
     info!("Server started on http://{addr}");
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, service).await.unwrap();
 }
@@ -16,7 +16,7 @@ pub async fn log_mw(req: Request<Body>, next: Next) -> Response {
 
     let res = next.run(req).await;
 
-    let sensitive_fields = ["code", "client_secret"];
+    let sensitive_fields = ["code", "client_secret", "token", "refresh_token"];
 
     let v_query_fields: Vec<&str> = uri.query().unwrap_or("").split('&').collect();
 
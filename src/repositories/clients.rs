@@ -1,17 +1,212 @@
+use serde::{Deserialize, Serialize};
 use serde_json::from_str;
 use sqlx::{MySql, Pool};
 
-use crate::services::{AuthorizeInput, TokenInput};
+use crate::services::TokenInput;
 
 // TODO: clean this up later
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Client {
     pub id: u64,
     pub name: String,
     pub secret_hash: String,
     pub scopes: Option<Vec<String>>,
     pub redirect_uris: Option<Vec<String>>,
+    pub grant_types: Option<Vec<String>>,
+    pub settings: ClientSettings,
+}
+
+impl Client {
+    pub fn allows_grant(&self, grant_type: &str) -> bool {
+        self.grant_types
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .any(|g| g == grant_type)
+    }
+
+    // exact match, RFC 6749 section 3.1.2.3
+    pub fn has_redirect_uri(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .any(|uri| uri == redirect_uri)
+    }
+}
+
+// Per-client policy columns on the clients row
+#[derive(Debug, Default, Clone)]
+pub struct ClientSettings {
+    pub require_pkce: bool,
+    // RFC 9126: authorization requests must be pushed to /par first
+    pub require_pushed_authorization_requests: bool,
+    // RFC 9101: authorization requests must come as a request object signed with the client's keys
+    pub require_signed_request_object: bool,
+    pub token_endpoint_auth_method: TokenEndpointAuthMethod,
+    // private_key_jwt: inline JWKS (JSON) or where to fetch it
+    pub jwks: Option<String>,
+    pub jwks_uri: Option<String>,
+    // tls_client_auth: expected certificate subject, RFC 4514 string form
+    pub tls_client_auth_subject_dn: Option<String>,
+    pub access_token_format: AccessTokenFormat,
+    pub lifetimes: LifetimeOverrides,
+    // RFC 9396: JSON object, authorization details type -> JSON Schema
+    pub authorization_details_types: Option<String>,
+    // how /authorize may hand back its result
+    pub response_modes: Vec<ResponseMode>,
+    // JARM: JWE alg and enc for authorization responses, unencrypted when None
+    pub authorization_encrypted_response_alg: Option<String>,
+    pub authorization_encrypted_response_enc: Option<String>,
+}
+
+// Per-client lifetimes in seconds, None falls back to the server default (see `services::lifetimes`)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LifetimeOverrides {
+    pub access_token: Option<u64>,
+    pub refresh_token_idle: Option<u64>,
+    pub refresh_token_absolute: Option<u64>,
+    pub auth_code: Option<u64>,
+    pub session: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccessTokenFormat {
+    #[default]
+    Jwt,
+    // random handle, only resolvable through introspection
+    Opaque,
+}
+
+impl AccessTokenFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccessTokenFormat::Jwt => "jwt",
+            AccessTokenFormat::Opaque => "opaque",
+        }
+    }
+
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "jwt" => Some(AccessTokenFormat::Jwt),
+            "opaque" => Some(AccessTokenFormat::Opaque),
+            _ => None,
+        }
+    }
+}
+
+// OAuth 2.0 Multiple Response Type Encoding Practices, Form Post and Web Message response modes,
+// and their JARM counterparts that carry the parameters as one signed `response` JWT
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseMode {
+    #[default]
+    Query,
+    Fragment,
+    FormPost,
+    WebMessage,
+    #[serde(rename = "query.jwt")]
+    QueryJwt,
+    #[serde(rename = "fragment.jwt")]
+    FragmentJwt,
+    #[serde(rename = "form_post.jwt")]
+    FormPostJwt,
+}
+
+impl ResponseMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResponseMode::Query => "query",
+            ResponseMode::Fragment => "fragment",
+            ResponseMode::FormPost => "form_post",
+            ResponseMode::WebMessage => "web_message",
+            ResponseMode::QueryJwt => "query.jwt",
+            ResponseMode::FragmentJwt => "fragment.jwt",
+            ResponseMode::FormPostJwt => "form_post.jwt",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "query" => Some(ResponseMode::Query),
+            "fragment" => Some(ResponseMode::Fragment),
+            "form_post" => Some(ResponseMode::FormPost),
+            "web_message" => Some(ResponseMode::WebMessage),
+            // JARM section 2.3.4: the default for response_type=code is query.jwt
+            "jwt" | "query.jwt" => Some(ResponseMode::QueryJwt),
+            "fragment.jwt" => Some(ResponseMode::FragmentJwt),
+            "form_post.jwt" => Some(ResponseMode::FormPostJwt),
+            _ => None,
+        }
+    }
+
+    // How the JARM modes deliver their `response` JWT, None for the plain modes
+    pub fn jwt_delivery(self) -> Option<ResponseMode> {
+        match self {
+            ResponseMode::QueryJwt => Some(ResponseMode::Query),
+            ResponseMode::FragmentJwt => Some(ResponseMode::Fragment),
+            ResponseMode::FormPostJwt => Some(ResponseMode::FormPost),
+            _ => None,
+        }
+    }
+}
+
+fn parse_response_modes(modes: &str) -> Vec<ResponseMode> {
+    modes
+        .split_whitespace()
+        .filter_map(ResponseMode::parse)
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TokenEndpointAuthMethod {
+    // both secret methods accept the secret either way
+    #[default]
+    ClientSecretBasic,
+    ClientSecretPost,
+    PrivateKeyJwt,
+    // RFC 8705, the client certificate from the TLS handshake authenticates the client
+    TlsClientAuth,
+    SelfSignedTlsClientAuth,
+}
+
+impl TokenEndpointAuthMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenEndpointAuthMethod::ClientSecretBasic => "client_secret_basic",
+            TokenEndpointAuthMethod::ClientSecretPost => "client_secret_post",
+            TokenEndpointAuthMethod::PrivateKeyJwt => "private_key_jwt",
+            TokenEndpointAuthMethod::TlsClientAuth => "tls_client_auth",
+            TokenEndpointAuthMethod::SelfSignedTlsClientAuth => "self_signed_tls_client_auth",
+        }
+    }
+
+    pub fn uses_secret(self) -> bool {
+        matches!(
+            self,
+            TokenEndpointAuthMethod::ClientSecretBasic | TokenEndpointAuthMethod::ClientSecretPost
+        )
+    }
+
+    pub fn uses_certificate(self) -> bool {
+        matches!(
+            self,
+            TokenEndpointAuthMethod::TlsClientAuth
+                | TokenEndpointAuthMethod::SelfSignedTlsClientAuth
+        )
+    }
+
+    pub fn parse(method: &str) -> Option<Self> {
+        match method {
+            "client_secret_basic" => Some(TokenEndpointAuthMethod::ClientSecretBasic),
+            "client_secret_post" => Some(TokenEndpointAuthMethod::ClientSecretPost),
+            "private_key_jwt" => Some(TokenEndpointAuthMethod::PrivateKeyJwt),
+            "tls_client_auth" => Some(TokenEndpointAuthMethod::TlsClientAuth),
+            "self_signed_tls_client_auth" => Some(TokenEndpointAuthMethod::SelfSignedTlsClientAuth),
+            _ => None,
+        }
+    }
 }
 
 pub async fn create_client(
@@ -21,14 +216,39 @@ pub async fn create_client(
     redirect_uris: &[String],
     grant_types: &[String],
     scopes: &[String],
+    settings: &ClientSettings,
 ) -> sqlx::Result<u64> {
     let result = sqlx::query!(
         r#"
-        INSERT INTO clients (client_id, client_secret_hash)
-        VALUES (?, ?)
+        INSERT INTO clients (client_id, client_secret_hash, require_pkce, require_pushed_authorization_requests, require_signed_request_object, token_endpoint_auth_method, jwks, jwks_uri, tls_client_auth_subject_dn, access_token_format,
+          access_token_lifetime_secs, refresh_token_idle_lifetime_secs, refresh_token_absolute_lifetime_secs, auth_code_lifetime_secs, session_lifetime_secs,
+          authorization_details_types, response_modes, authorization_encrypted_response_alg, authorization_encrypted_response_enc)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#,
         client_id,
-        client_secret_hash
+        client_secret_hash,
+        settings.require_pkce,
+        settings.require_pushed_authorization_requests,
+        settings.require_signed_request_object,
+        settings.token_endpoint_auth_method.as_str(),
+        settings.jwks,
+        settings.jwks_uri,
+        settings.tls_client_auth_subject_dn,
+        settings.access_token_format.as_str(),
+        settings.lifetimes.access_token,
+        settings.lifetimes.refresh_token_idle,
+        settings.lifetimes.refresh_token_absolute,
+        settings.lifetimes.auth_code,
+        settings.lifetimes.session,
+        settings.authorization_details_types,
+        settings
+            .response_modes
+            .iter()
+            .map(|mode| mode.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        settings.authorization_encrypted_response_alg,
+        settings.authorization_encrypted_response_enc
     )
     .execute(pool)
     .await?;
@@ -87,29 +307,50 @@ pub async fn create_client(
     Ok(result.last_insert_id())
 }
 
-pub async fn get_by_client_id(
-    pool: &Pool<MySql>,
-    authorize_input: &AuthorizeInput,
-) -> sqlx::Result<Option<Client>> {
+// The one query that reads a client, every lookup goes through it so a new column is mapped once
+pub async fn get_by_client_id(pool: &Pool<MySql>, client_id: &str) -> sqlx::Result<Option<Client>> {
     let row = sqlx::query!(
         r#"
         SELECT
           c.id AS `id!: u64`,
           c.client_id AS `name!`,
           c.client_secret_hash AS `secret_hash!`,
-          CAST(COALESCE(JSON_ARRAYAGG(cs.scope), JSON_ARRAY()) AS CHAR) AS `scopes_json!`,
-          CAST(COALESCE(JSON_ARRAYAGG(cru.redirect_uri), JSON_ARRAY()) AS CHAR) AS `redirect_uris_json!`
+          c.require_pkce AS `require_pkce!: bool`,
+          c.require_pushed_authorization_requests AS `require_pushed_authorization_requests!: bool`,
+          c.require_signed_request_object AS `require_signed_request_object!: bool`,
+          c.token_endpoint_auth_method AS `token_endpoint_auth_method!`,
+          c.jwks AS `jwks?`,
+          c.jwks_uri AS `jwks_uri?`,
+          c.tls_client_auth_subject_dn AS `tls_client_auth_subject_dn?`,
+          c.access_token_format AS `access_token_format!`,
+          c.access_token_lifetime_secs AS `access_token_lifetime_secs?: u64`,
+          c.refresh_token_idle_lifetime_secs AS `refresh_token_idle_lifetime_secs?: u64`,
+          c.refresh_token_absolute_lifetime_secs AS `refresh_token_absolute_lifetime_secs?: u64`,
+          c.auth_code_lifetime_secs AS `auth_code_lifetime_secs?: u64`,
+          c.session_lifetime_secs AS `session_lifetime_secs?: u64`,
+          c.authorization_details_types AS `authorization_details_types?`,
+          c.response_modes AS `response_modes!`,
+          c.authorization_encrypted_response_alg AS `authorization_encrypted_response_alg?`,
+          c.authorization_encrypted_response_enc AS `authorization_encrypted_response_enc?`,
+          (
+            SELECT CAST(COALESCE(JSON_ARRAYAGG(cs.scope), JSON_ARRAY()) AS CHAR)
+            FROM client_scopes cs
+            WHERE cs.client_id_ref = c.id
+          ) AS `scopes_json!`,
+          (
+            SELECT CAST(COALESCE(JSON_ARRAYAGG(cru.redirect_uri), JSON_ARRAY()) AS CHAR)
+            FROM client_redirect_uris cru
+            WHERE cru.client_id_ref = c.id
+          ) AS `redirect_uris_json!`,
+          (
+            SELECT CAST(COALESCE(JSON_ARRAYAGG(cgt.grant_type), JSON_ARRAY()) AS CHAR)
+            FROM client_grant_types cgt
+            WHERE cgt.client_id_ref = c.id
+          ) AS `grant_types_json!`
         FROM clients c
-        LEFT JOIN client_scopes cs ON cs.client_id_ref = c.id
-        LEFT JOIN client_redirect_uris cru ON cru.client_id_ref = c.id
         WHERE c.client_id = ?
-        AND cru.redirect_uri = ?
-        AND cs.scope IN (?)
-        GROUP BY c.id, c.client_id, c.client_secret_hash
         "#,
-        authorize_input.client_id,
-        authorize_input.redirect_uri,
-        authorize_input.scopes.join(",")
+        client_id
     )
     .fetch_optional(pool)
     .await?;
@@ -120,39 +361,45 @@ pub async fn get_by_client_id(
         secret_hash: r.secret_hash,
         scopes: Some(from_str::<Vec<String>>(&r.scopes_json).unwrap_or_default()),
         redirect_uris: Some(from_str::<Vec<String>>(&r.redirect_uris_json).unwrap_or_default()),
+        grant_types: Some(from_str::<Vec<String>>(&r.grant_types_json).unwrap_or_default()),
+        settings: ClientSettings {
+            require_pkce: r.require_pkce,
+            require_pushed_authorization_requests: r.require_pushed_authorization_requests,
+            require_signed_request_object: r.require_signed_request_object,
+            token_endpoint_auth_method: TokenEndpointAuthMethod::parse(
+                &r.token_endpoint_auth_method,
+            )
+            .unwrap_or_default(),
+            jwks: r.jwks,
+            jwks_uri: r.jwks_uri,
+            tls_client_auth_subject_dn: r.tls_client_auth_subject_dn,
+            access_token_format: AccessTokenFormat::parse(&r.access_token_format)
+                .unwrap_or_default(),
+            lifetimes: LifetimeOverrides {
+                access_token: r.access_token_lifetime_secs,
+                refresh_token_idle: r.refresh_token_idle_lifetime_secs,
+                refresh_token_absolute: r.refresh_token_absolute_lifetime_secs,
+                auth_code: r.auth_code_lifetime_secs,
+                session: r.session_lifetime_secs,
+            },
+            authorization_details_types: r.authorization_details_types,
+            response_modes: parse_response_modes(&r.response_modes),
+            authorization_encrypted_response_alg: r.authorization_encrypted_response_alg,
+            authorization_encrypted_response_enc: r.authorization_encrypted_response_enc,
+        },
     }))
 }
 
+// The client behind a token request, for the authorization_code grant only if `redirect_uri` is registered
 pub async fn get_by_client_token(
     pool: &Pool<MySql>,
     token_input: &TokenInput,
 ) -> sqlx::Result<Option<Client>> {
-    let row = sqlx::query!(
-        r#"
-        SELECT
-          c.id AS `id!: u64`,
-          c.client_id AS `name!`,
-          c.client_secret_hash AS `secret_hash!`,
-          CAST(COALESCE(JSON_ARRAYAGG(cs.scope), JSON_ARRAY()) AS CHAR) AS `scopes_json!`,
-          CAST(COALESCE(JSON_ARRAYAGG(cru.redirect_uri), JSON_ARRAY()) AS CHAR) AS `redirect_uris_json!`
-        FROM clients c
-        LEFT JOIN client_scopes cs ON cs.client_id_ref = c.id
-        LEFT JOIN client_redirect_uris cru ON cru.client_id_ref = c.id
-        WHERE c.client_id = ?
-        AND cru.redirect_uri = ?
-        GROUP BY c.id, c.client_id, c.client_secret_hash
-        "#,
-        token_input.client_id,
-        token_input.redirect_uri,
-    )
-    .fetch_optional(pool)
-    .await?;
-
-    Ok(row.map(|r| Client {
-        id: r.id,
-        name: r.name,
-        secret_hash: r.secret_hash,
-        scopes: None,
-        redirect_uris: None,
+    let client = get_by_client_id(pool, &token_input.client_id).await?;
+    Ok(client.filter(|client| {
+        token_input
+            .redirect_uri
+            .as_deref()
+            .is_none_or(|uri| client.has_redirect_uri(uri))
     }))
 }
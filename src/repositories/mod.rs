@@ -1,2 +1,5 @@
 pub mod clients;
+pub mod refresh_tokens;
+pub mod resources;
+pub mod signing_keys;
 pub mod users;
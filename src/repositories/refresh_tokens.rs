@@ -0,0 +1,228 @@
+use sqlx::{Executor, MySql, Pool};
+
+#[derive(Debug)]
+pub struct RefreshToken {
+    pub id: u64,
+    pub family_id: String,
+    pub client_id_ref: u64,
+    pub client_id: String,
+    pub user_id: String,
+    pub scope: String,
+    pub authorization_details: Option<String>, // JSON
+    pub dpop_jkt: Option<String>,
+    pub issued_at: i64,  // unix seconds
+    pub expires_at: i64, // unix seconds
+    // until the family reaches its absolute lifetime
+    pub family_remaining_secs: u64,
+    pub expired: bool,
+    pub rotated: bool,
+    pub revoked: bool,
+}
+
+// Everything but the token itself, shared by all tokens of a family
+#[derive(Debug)]
+pub struct NewRefreshToken<'a> {
+    pub family_id: &'a str,
+    pub client_id_ref: u64,
+    pub user_id: &'a str,
+    pub scope: &'a str,
+    // RFC 9396, JSON array the user approved
+    pub authorization_details: Option<&'a str>,
+    // DPoP key the family is bound to (RFC 9449 section 5)
+    pub dpop_jkt: Option<&'a str>,
+    // until the family reaches its absolute lifetime, the token itself never outlives it
+    pub family_ttl_secs: u64,
+}
+
+#[derive(Debug)]
+pub struct DerivedAccessToken {
+    pub jti: String,
+    pub remaining_secs: u64,
+}
+
+async fn insert_refresh_token<'e>(
+    executor: impl Executor<'e, Database = MySql>,
+    token_hash: &str,
+    new_token: &NewRefreshToken<'_>,
+    ttl_secs: u64,
+) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO refresh_tokens (token_hash, family_id, client_id_ref, user_id, scope, authorization_details, dpop_jkt, expires_at, family_expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, DATE_ADD(NOW(), INTERVAL ? SECOND), DATE_ADD(NOW(), INTERVAL ? SECOND))
+        "#,
+        token_hash,
+        new_token.family_id,
+        new_token.client_id_ref,
+        new_token.user_id,
+        new_token.scope,
+        new_token.authorization_details,
+        new_token.dpop_jkt,
+        ttl_secs.min(new_token.family_ttl_secs),
+        new_token.family_ttl_secs
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.last_insert_id())
+}
+
+pub async fn create_refresh_token(
+    pool: &Pool<MySql>,
+    token_hash: &str,
+    new_token: &NewRefreshToken<'_>,
+    ttl_secs: u64,
+) -> sqlx::Result<u64> {
+    insert_refresh_token(pool, token_hash, new_token, ttl_secs).await
+}
+
+pub async fn get_by_token_hash(
+    pool: &Pool<MySql>,
+    token_hash: &str,
+) -> sqlx::Result<Option<RefreshToken>> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+          rt.id AS `id!: u64`,
+          rt.family_id AS `family_id!`,
+          rt.client_id_ref AS `client_id_ref!: u64`,
+          c.client_id AS `client_id!`,
+          rt.user_id AS `user_id!`,
+          rt.scope AS `scope!`,
+          rt.authorization_details AS `authorization_details?`,
+          rt.dpop_jkt AS `dpop_jkt?`,
+          CAST(UNIX_TIMESTAMP(rt.created_at) AS SIGNED) AS `issued_at!: i64`,
+          CAST(UNIX_TIMESTAMP(rt.expires_at) AS SIGNED) AS `expires_at!: i64`,
+          TIMESTAMPDIFF(SECOND, NOW(), COALESCE(rt.family_expires_at, rt.expires_at)) AS `family_remaining_secs!: i64`,
+          (rt.expires_at <= NOW()) AS `expired!: bool`,
+          (rt.rotated_at IS NOT NULL) AS `rotated!: bool`,
+          (rt.revoked_at IS NOT NULL) AS `revoked!: bool`
+        FROM refresh_tokens rt
+        JOIN clients c ON c.id = rt.client_id_ref
+        WHERE rt.token_hash = ?
+        "#,
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|r| RefreshToken {
+        id: r.id,
+        family_id: r.family_id,
+        client_id_ref: r.client_id_ref,
+        client_id: r.client_id,
+        user_id: r.user_id,
+        scope: r.scope,
+        authorization_details: r.authorization_details,
+        dpop_jkt: r.dpop_jkt,
+        issued_at: r.issued_at,
+        expires_at: r.expires_at,
+        family_remaining_secs: u64::try_from(r.family_remaining_secs).unwrap_or_default(),
+        expired: r.expired,
+        rotated: r.rotated,
+        revoked: r.revoked,
+    }))
+}
+
+// Marks token `id` as rotated and stores its successor, in one transaction so a family is never
+// left with a rotated token and no successor. None if `id` was already rotated or revoked,
+// which means someone else redeemed it first.
+pub async fn replace_refresh_token(
+    pool: &Pool<MySql>,
+    id: u64,
+    token_hash: &str,
+    new_token: &NewRefreshToken<'_>,
+    ttl_secs: u64,
+) -> sqlx::Result<Option<u64>> {
+    let mut tx = pool.begin().await?;
+
+    let rotated = sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET rotated_at = NOW()
+        WHERE id = ?
+        AND rotated_at IS NULL
+        AND revoked_at IS NULL
+        "#,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if rotated.rows_affected() != 1 {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+
+    let successor = insert_refresh_token(&mut *tx, token_hash, new_token, ttl_secs).await?;
+
+    tx.commit().await?;
+    Ok(Some(successor))
+}
+
+pub async fn revoke_family(pool: &Pool<MySql>, family_id: &str) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET revoked_at = NOW()
+        WHERE family_id = ?
+        AND revoked_at IS NULL
+        "#,
+        family_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn set_access_token_jti(
+    pool: &Pool<MySql>,
+    id: u64,
+    jti: &str,
+    access_token_ttl_secs: u64,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET access_token_jti = ?, access_token_expires_at = DATE_ADD(NOW(), INTERVAL ? SECOND)
+        WHERE id = ?
+        "#,
+        jti,
+        access_token_ttl_secs,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// Access tokens issued alongside the family's refresh tokens that have not expired yet
+pub async fn get_derived_access_tokens(
+    pool: &Pool<MySql>,
+    family_id: &str,
+) -> sqlx::Result<Vec<DerivedAccessToken>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+          rt.access_token_jti AS `jti!`,
+          TIMESTAMPDIFF(SECOND, NOW(), rt.access_token_expires_at) AS `remaining_secs!: i64`
+        FROM refresh_tokens rt
+        WHERE rt.family_id = ?
+        AND rt.access_token_jti IS NOT NULL
+        AND rt.access_token_expires_at > NOW()
+        "#,
+        family_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| DerivedAccessToken {
+            jti: r.jti,
+            remaining_secs: u64::try_from(r.remaining_secs).unwrap_or_default(),
+        })
+        .collect())
+}
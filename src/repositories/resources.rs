@@ -0,0 +1,68 @@
+use serde_json::from_str;
+use sqlx::{MySql, Pool};
+
+#[derive(Debug)]
+pub struct Resource {
+    pub identifier: String,
+    pub scopes: Vec<String>,
+}
+
+pub async fn create_resource(
+    pool: &Pool<MySql>,
+    identifier: &str,
+    name: &str,
+    scopes: &[String],
+) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO resources (identifier, name)
+        VALUES (?, ?)
+        "#,
+        identifier,
+        name
+    )
+    .execute(pool)
+    .await?;
+
+    for scope in scopes {
+        sqlx::query!(
+            r#"
+            INSERT INTO resource_scopes (resource_id_ref, scope)
+            VALUES (?, ?)
+            "#,
+            result.last_insert_id(),
+            scope
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(result.last_insert_id())
+}
+
+pub async fn get_by_identifier(
+    pool: &Pool<MySql>,
+    identifier: &str,
+) -> sqlx::Result<Option<Resource>> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+          r.identifier AS `identifier!`,
+          (
+            SELECT CAST(COALESCE(JSON_ARRAYAGG(rs.scope), JSON_ARRAY()) AS CHAR)
+            FROM resource_scopes rs
+            WHERE rs.resource_id_ref = r.id
+          ) AS `scopes_json!`
+        FROM resources r
+        WHERE r.identifier = ?
+        "#,
+        identifier
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|r| Resource {
+        identifier: r.identifier,
+        scopes: from_str::<Vec<String>>(&r.scopes_json).unwrap_or_default(),
+    }))
+}
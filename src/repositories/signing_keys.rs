@@ -0,0 +1,174 @@
+use sqlx::{MySql, Pool};
+
+#[derive(Debug)]
+pub struct SigningKeySchedule {
+    pub id: u64,
+    pub kid: String,
+    pub alg: String,
+    pub status: String,
+    // seconds since activation for active keys, since creation for pending ones
+    pub age_secs: u64,
+}
+
+#[derive(Debug)]
+pub struct SigningKeyRow {
+    pub kid: String,
+    pub alg: String,
+    // DER, or sealed with SIGNING_KEY_ENCRYPTION_KEY when `private_key_encrypted`
+    pub private_key: Vec<u8>,
+    pub private_key_encrypted: bool,
+    pub public_jwk: String,
+    pub status: String,
+}
+
+pub async fn create_signing_key(
+    pool: &Pool<MySql>,
+    kid: &str,
+    alg: &str,
+    private_key: &[u8],
+    private_key_encrypted: bool,
+    public_jwk: &str,
+    status: &str,
+) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO signing_keys (kid, alg, private_key, private_key_encrypted, public_jwk, status, activated_at)
+        VALUES (?, ?, ?, ?, ?, ?, IF(? = 'active', NOW(), NULL))
+        "#,
+        kid,
+        alg,
+        private_key,
+        private_key_encrypted,
+        public_jwk,
+        status,
+        status
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.last_insert_id())
+}
+
+// Pending and active keys plus keys retired less than `retired_grace_secs` ago, newest first
+pub async fn get_published_signing_keys(
+    pool: &Pool<MySql>,
+    retired_grace_secs: u64,
+) -> sqlx::Result<Vec<SigningKeyRow>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+          sk.kid AS `kid!`,
+          sk.alg AS `alg!`,
+          sk.private_key AS `private_key!: Vec<u8>`,
+          sk.private_key_encrypted AS `private_key_encrypted!: bool`,
+          sk.public_jwk AS `public_jwk!`,
+          sk.status AS `status!`
+        FROM signing_keys sk
+        WHERE sk.status IN ('pending', 'active')
+           OR (sk.status = 'retired' AND sk.retired_at > DATE_SUB(NOW(), INTERVAL ? SECOND))
+        ORDER BY sk.created_at DESC, sk.id DESC
+        "#,
+        retired_grace_secs
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| SigningKeyRow {
+            kid: r.kid,
+            alg: r.alg,
+            private_key: r.private_key,
+            private_key_encrypted: r.private_key_encrypted,
+            public_jwk: r.public_jwk,
+            status: r.status,
+        })
+        .collect())
+}
+
+// Pending and active keys of every algorithm, newest first
+pub async fn get_signing_key_schedule(pool: &Pool<MySql>) -> sqlx::Result<Vec<SigningKeySchedule>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+          sk.id,
+          sk.kid AS `kid!`,
+          sk.alg AS `alg!`,
+          sk.status AS `status!`,
+          TIMESTAMPDIFF(SECOND, COALESCE(sk.activated_at, sk.created_at), NOW()) AS `age_secs!: i64`
+        FROM signing_keys sk
+        WHERE sk.status IN ('pending', 'active')
+        ORDER BY sk.created_at DESC, sk.id DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| SigningKeySchedule {
+            id: r.id,
+            kid: r.kid,
+            alg: r.alg,
+            status: r.status,
+            age_secs: u64::try_from(r.age_secs).unwrap_or_default(),
+        })
+        .collect())
+}
+
+// Promotes a pending key and retires every other key, whatever its alg (TOKEN_SIGNING_ALG may
+// have changed), in one transaction
+pub async fn activate_signing_key(pool: &Pool<MySql>, id: u64) -> sqlx::Result<bool> {
+    let mut tx = pool.begin().await?;
+
+    let activated = sqlx::query!(
+        r#"
+        UPDATE signing_keys
+        SET status = 'active', activated_at = NOW()
+        WHERE id = ?
+        AND status = 'pending'
+        "#,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if activated.rows_affected() != 1 {
+        tx.rollback().await?;
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE signing_keys
+        SET status = 'retired', retired_at = NOW()
+        WHERE status IN ('pending', 'active')
+        AND id <> ?
+        "#,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Drops retired keys (private key included) once nothing they signed can still be valid
+pub async fn delete_retired_signing_keys(
+    pool: &Pool<MySql>,
+    retired_grace_secs: u64,
+) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM signing_keys
+        WHERE status = 'retired'
+        AND retired_at <= DATE_SUB(NOW(), INTERVAL ? SECOND)
+        "#,
+        retired_grace_secs
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -1,43 +1,166 @@
-use crate::{routes::COOKIE_NAME, services::cache::get_cookie, state::AppState};
+use crate::{routes::COOKIE_NAME, services::user::get_session, state::AppState};
 use axum::{
-    Json, extract::{Query, State, rejection::QueryRejection}, http::StatusCode, response::IntoResponse
+    extract::{
+        rejection::{FormRejection, QueryRejection},
+        Form, Query, State,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
 };
 use axum_extra::extract::CookieJar;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::json;
+use tracing::error;
+use url::{form_urlencoded, Url};
 
+use crate::repositories::clients::{Client, ResponseMode};
+use crate::routes::html::{escape_html, login_required, page};
+use crate::services::authorize::{
+    complete_consent, redeem_request_uri, AuthorizationRequest, AuthorizeFailure, AuthorizeOutcome,
+    AuthorizeResult, ConsentOutcome, ErrorRedirect, PendingConsent,
+};
+use crate::services::jarm::authorization_response;
+use crate::services::request_object::{verify_request_object, RequestObject};
 use crate::services::{authorize_svc, AuthorizeInput};
 
 #[derive(Deserialize, Debug)]
 pub struct AuthorizeQuery {
     client_id: Option<String>,
+    // RFC 9126: stands in for everything below, as pushed to /par
+    request_uri: Option<String>,
+    // RFC 9101: a request object, its claims take precedence over the parameters below
+    request: Option<String>,
+    #[serde(flatten)]
+    params: AuthorizationParams,
+}
+
+// The authorization request itself, sent to /authorize or pushed to /par
+#[derive(Deserialize, Debug)]
+pub struct AuthorizationParams {
     response_type: Option<String>,
+    // query (default), fragment, form_post or web_message, as registered by the client
+    response_mode: Option<String>,
     redirect_uri: Option<String>,
     scope: Option<String>,
     state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    resource: Option<String>,
+    authorization_details: Option<String>,
 }
 
-#[derive(Serialize)]
-struct AuthorizeResponse {
-    redirect_to: String,
-    code: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    state: Option<String>,
+impl AuthorizationParams {
+    // RFC 9101 section 6.3: what the signed request object says wins over the plain parameters
+    fn merge(&mut self, object: RequestObject) {
+        let RequestObject {
+            response_type,
+            response_mode,
+            redirect_uri,
+            scope,
+            state,
+            code_challenge,
+            code_challenge_method,
+            resource,
+            authorization_details,
+            ..
+        } = object;
+        self.response_type = response_type.or(self.response_type.take());
+        self.response_mode = response_mode.or(self.response_mode.take());
+        self.redirect_uri = redirect_uri.or(self.redirect_uri.take());
+        self.scope = scope.or(self.scope.take());
+        self.state = state.or(self.state.take());
+        self.code_challenge = code_challenge.or(self.code_challenge.take());
+        self.code_challenge_method = code_challenge_method.or(self.code_challenge_method.take());
+        self.resource = resource.or(self.resource.take());
+        self.authorization_details = authorization_details
+            .map(|details| details.to_string())
+            .or(self.authorization_details.take());
+    }
+
+    // Verifies the request object (if any) and lets its claims override the parameters
+    pub async fn apply_request_object(
+        &mut self,
+        app: &AppState,
+        client_id: &str,
+        request: Option<&str>,
+    ) -> Result<bool, String> {
+        let Some(request) = request else {
+            return Ok(false);
+        };
+        let object = verify_request_object(app, client_id, request)
+            .await
+            .map_err(|err| err.to_string())?;
+        self.merge(object);
+        Ok(true)
+    }
+
+    // Without a redirect_uri there is nowhere to send the result, everything else is checked later
+    pub fn into_request(self, client_id: String, signed: bool) -> Option<AuthorizationRequest> {
+        Some(AuthorizationRequest {
+            client_id,
+            redirect_uri: self.redirect_uri?,
+            response_type: self.response_type,
+            response_mode: self.response_mode,
+            scopes: self.scope.as_deref().map_or_else(Vec::new, |s| {
+                s.split_whitespace().map(str::to_string).collect()
+            }),
+            state: self.state,
+            code_challenge: self.code_challenge,
+            code_challenge_method: self.code_challenge_method,
+            resource: self.resource,
+            authorization_details: self.authorization_details,
+            signed,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConsentForm {
+    consent_id: String,
+    action: String,
 }
 
 /*
 * GET /authorize?client_id=...
     &response_type=...
+    &response_mode=query|fragment|form_post|web_message|jwt|query.jwt|fragment.jwt|form_post.jwt
+      (optional, one the client registered, query by default)
     &redirect_uri=...
     &scope=...
     &state=...
+    &code_challenge=...
+    &code_challenge_method=S256|plain
+    &resource=... (optional, RFC 8707: a registered API, scopes must belong to it)
+    &authorization_details=[{"type": "...", ...}] (optional, RFC 9396, URL-encoded JSON)
+    &request=<signed JWT> (optional, RFC 9101: the parameters above as claims, these win over the query)
+* or
+* GET /authorize?client_id=...&request_uri=urn:ietf:params:oauth:request_uri:... (RFC 9126, from POST /par)
 
 * OUTPUT
-* Redirect to:
-* 302 ${redirect_uri}?code=AUTH_CODE&state=STATE
+* 302 Location: ${redirect_uri}?code=AUTH_CODE&state=STATE
+* 302 Location: ${redirect_uri}?error=...&error_description=...&state=STATE once client and redirect_uri are verified
+*     fragment: the same parameters after # instead of ?
+*     form_post: 200 HTML form that POSTs the parameters to redirect_uri on load
+*     web_message: 200 HTML page that postMessages { "type": "authorization_response", "response": {...} }
+*                  to window.opener (or the parent frame) at the redirect_uri's origin
+*     jwt (= query.jwt), query.jwt, fragment.jwt, form_post.jwt: one "response" parameter instead, a JWT signed
+*                  with Loom's key holding the parameters plus iss, aud = client_id and exp,
+*                  encrypted to the client's key when it registered authorization_encrypted_response_alg
+* 200 consent page when authorization_details were sent, the user approves them at POST /authorize/consent
+* 400 error page when the client, redirect_uri, request or request_uri is invalid, nothing is sent to the redirect_uri
+* 401 sign-in page when the user is not logged in, or logged in longer ago than the client's session lifetime
 *
+* VALIDATE
+* client_id is registered and redirect_uri is one of its redirect_uris (exact match)
+* request_uri: pushed by this client, not expired or used before; other parameters are ignored
+* clients registered with require_pushed_authorization_requests must use a request_uri
+* request: signed with a key from the client's jwks / jwks_uri, iss = client_id, aud = issuer, not expired
+* clients registered with require_signed_request_object must send a request (directly or pushed to /par)
+* response_type is code, scopes are registered for the client
+* authorization_details: each entry has a type registered by the client and matches its JSON Schema
+
 * CORE LOGIC
-* Create an authorization code (short-lived, 5-10 min), store it in db, bind it to client, user, redirect_uri
+* Create an authorization code (short-lived, the client's auth code lifetime), store it in db, bind it to client, user, redirect_uri
 */
 
 #[axum::debug_handler]
@@ -46,110 +169,433 @@ pub async fn authorize(
     jar: CookieJar,
     aq: Result<Query<AuthorizeQuery>, QueryRejection>,
 ) -> impl IntoResponse {
-    let aq = match aq {
+    let mut aq = match aq {
         Ok(Query(aq)) => aq,
         Err(err) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(json!({ "error": "invalid_query", "detail": err.to_string() })),
-            ).into_response();
+            return error_page(StatusCode::BAD_REQUEST, "Invalid request", &err.to_string())
         }
     };
 
-    let Some(client_id) = aq.client_id.as_deref() else {
-        return (
+    let Some(client_id) = aq.client_id.clone() else {
+        return error_page(
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "invalid_request", "detail": "client_id is required" })),
-        ).into_response();
+            "Invalid request",
+            "client_id is required",
+        );
     };
 
-    let Some(rt) = aq.response_type.as_deref() else {
-        return (
+    if aq.request.is_some() && aq.request_uri.is_some() {
+        return error_page(
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "invalid_request", "detail": "response_type is required" })),
-        ).into_response();
+            "Invalid request",
+            "request and request_uri must not both be present",
+        );
+    }
+    let signed = match aq
+        .params
+        .apply_request_object(&app, &client_id, aq.request.as_deref())
+        .await
+    {
+        Ok(signed) => signed,
+        Err(detail) => {
+            return error_page(StatusCode::BAD_REQUEST, "Invalid request object", &detail)
+        }
     };
 
-    if rt != "code" {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "unsupported_response_type", "detail": "only response_type=code is supported" })),
-        ).into_response();
+    let session = match jar.get(COOKIE_NAME) {
+        Some(c) => get_session(&app, c.value()).await,
+        Option::None => Ok(Option::None),
+    };
+    let session = match session {
+        Ok(Some(s)) => s,
+        Ok(Option::None) => return login_required(),
+        Err(err) => return server_error(&err),
+    };
+
+    // after the login check, a request_uri can only be used once
+    let pushed = aq.request_uri.is_some();
+    let request = match aq.request_uri.as_deref() {
+        Some(request_uri) => match redeem_request_uri(&app, &client_id, request_uri).await {
+            Ok(Some(request)) => request,
+            Ok(Option::None) => {
+                return error_page(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid request",
+                    "request_uri is unknown, expired or already used",
+                );
+            }
+            Err(err) => return server_error(&err),
+        },
+        Option::None => match aq.params.into_request(client_id, signed) {
+            Some(request) => request,
+            Option::None => {
+                return error_page(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid request",
+                    "redirect_uri is required",
+                );
+            }
+        },
+    };
+
+    match authorize_svc(
+        &app,
+        AuthorizeInput {
+            request,
+            pushed,
+            user_id: session.user_id,
+            authenticated_at: session.authenticated_at,
+        },
+    )
+    .await
+    {
+        Ok(AuthorizeOutcome::Code(res)) => code_redirect(&app, &res).await,
+        Ok(AuthorizeOutcome::Consent(pending)) => consent_page(&pending),
+        Err(AuthorizeFailure::InvalidClient(detail)) => {
+            error_page(StatusCode::BAD_REQUEST, "Invalid client", detail)
+        }
+        Err(AuthorizeFailure::LoginRequired) => login_required(),
+        Err(AuthorizeFailure::Redirect(redirect)) => error_redirect(&app, &redirect).await,
+        Err(AuthorizeFailure::Server(err)) => server_error(&err),
     }
+}
 
-    let redirect_uri = if let Some(uri) = aq.redirect_uri.as_deref() {
-        uri.to_string()
-    } else {
-        return (
+// For errors that must not go to the redirect_uri (RFC 6749 section 4.1.2.1)
+fn error_page(status: StatusCode, title: &str, detail: &str) -> Response {
+    (
+        status,
+        page(title, &format!("<p>{}</p>", escape_html(detail))),
+    )
+        .into_response()
+}
+
+// The details go to the log, not to the user
+fn server_error(err: &dyn std::fmt::Display) -> Response {
+    error!("Authorization request failed: {err}");
+    error_page(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Something went wrong",
+        "Please try again later.",
+    )
+}
+
+// RFC 6749 section 4.1.2 and the response modes: how the result gets to the redirect_uri
+fn redirect(
+    redirect_uri: &str,
+    response_mode: ResponseMode,
+    params: &[(&str, Option<&str>)],
+) -> Response {
+    let Ok(mut location) = Url::parse(redirect_uri) else {
+        return error_page(
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "invalid_request", "detail": "redirect_uri is required for this client" })),
-        ).into_response();
+            "Invalid client",
+            "redirect_uri is not a valid URL",
+        );
     };
+    let params: Vec<(&str, &str)> = params
+        .iter()
+        .filter_map(|(name, value)| Some((*name, (*value)?)))
+        .collect();
 
-    let requested_scopes: Vec<&str> = aq
-        .scope
-        .as_deref()
-        .map_or_else(Vec::new, |s| s.split_whitespace().collect());
+    match response_mode {
+        // next to any query the redirect_uri already has
+        ResponseMode::Query => {
+            location.query_pairs_mut().extend_pairs(&params);
+        }
+        ResponseMode::Fragment => {
+            let fragment = form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&params)
+                .finish();
+            location.set_fragment(Some(&fragment));
+        }
+        ResponseMode::FormPost => return form_post_page(&location, &params),
+        ResponseMode::WebMessage => return web_message_page(&location, &params),
+        // never with the plain parameters, see `respond`
+        ResponseMode::QueryJwt | ResponseMode::FragmentJwt | ResponseMode::FormPostJwt => {
+            return server_error(&"JWT response modes need a signed response");
+        }
+    }
+    (
+        StatusCode::FOUND,
+        [(header::LOCATION, location.to_string())],
+    )
+        .into_response()
+}
 
-    let scopes = requested_scopes
+// OAuth 2.0 Form Post Response Mode: the browser POSTs the parameters to the redirect_uri
+fn form_post_page(location: &Url, params: &[(&str, &str)]) -> Response {
+    let inputs = params
         .iter()
-        .map(|s| (*s).to_string())
-        .collect::<Vec<_>>();
-
-    let cookie = match jar.get(COOKIE_NAME) {
-        Some(c) => c.value(),
-        Option::None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({ "error": "unauthorized", "detail": "user not logged in" })),
-            ).into_response();
-        }
+        .map(|(name, value)| {
+            format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                escape_html(name),
+                escape_html(value)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        page(
+            "Signing you in",
+            &format!(
+                "<form method=\"post\" action=\"{}\">\n{inputs}\n\
+                 <noscript><button>Continue</button></noscript>\n\
+                 </form>\n\
+                 <script>document.forms[0].submit();</script>",
+                escape_html(location.as_str())
+            ),
+        ),
+    )
+        .into_response()
+}
+
+// Web message response mode: the page that opened the popup (or iframe) receives the parameters,
+// and only if it is on the redirect_uri's origin
+fn web_message_page(location: &Url, params: &[(&str, &str)]) -> Response {
+    let response: serde_json::Map<String, serde_json::Value> = params
+        .iter()
+        .map(|(name, value)| ((*name).to_string(), json!(value)))
+        .collect();
+    // "<" escaped so nothing in the values can close the script element
+    let message = json!({ "type": "authorization_response", "response": response })
+        .to_string()
+        .replace('<', "\\u003c");
+    let origin = json!(location.origin().ascii_serialization())
+        .to_string()
+        .replace('<', "\\u003c");
+
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        page(
+            "Signing you in",
+            &format!(
+                "<p>You can close this window.</p>\n\
+                 <script>(window.opener || window.parent).postMessage({message}, {origin});</script>"
+            ),
+        ),
+    )
+        .into_response()
+}
+
+// JARM: the parameters travel as one `response` JWT, delivered the way the plain mode would
+async fn respond(
+    app: &AppState,
+    client: &Client,
+    redirect_uri: &str,
+    response_mode: ResponseMode,
+    params: &[(&str, Option<&str>)],
+) -> Response {
+    let Some(delivery) = response_mode.jwt_delivery() else {
+        return redirect(redirect_uri, response_mode, params);
     };
+    let params: Vec<(&str, &str)> = params
+        .iter()
+        .filter_map(|(name, value)| Some((*name, (*value)?)))
+        .collect();
+    match authorization_response(app, client, &params).await {
+        Ok(response) => redirect(redirect_uri, delivery, &[("response", Some(&response))]),
+        Err(err) => server_error(&err),
+    }
+}
 
-    let email = match get_cookie(&app, cookie).await {
-        Ok(Some(e)) => e,
-        Ok(Option::None) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({ "error": "unauthorized", "detail": "invalid session" })),
-            ).into_response();
-        }
+async fn code_redirect(app: &AppState, res: &AuthorizeResult) -> Response {
+    respond(
+        app,
+        &res.client,
+        &res.redirect_uri,
+        res.response_mode,
+        &[("code", Some(&res.code)), ("state", res.state.as_deref())],
+    )
+    .await
+}
+
+async fn error_redirect(app: &AppState, redirect_to: &ErrorRedirect) -> Response {
+    respond(
+        app,
+        &redirect_to.client,
+        &redirect_to.redirect_uri,
+        redirect_to.response_mode,
+        &[
+            ("error", Some(redirect_to.error.error)),
+            ("error_description", Some(&redirect_to.error.description)),
+            ("state", redirect_to.state.as_deref()),
+        ],
+    )
+    .await
+}
+
+// RFC 9396 section 4: the user sees exactly what the client asks to be allowed to do
+fn consent_page(pending: &PendingConsent) -> Response {
+    let entries = pending
+        .authorization_details
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|entry| {
+            format!(
+                "<h2>{}</h2>\n<pre>{}</pre>",
+                escape_html(
+                    entry
+                        .get("type")
+                        .and_then(|t| t.as_str())
+                        .unwrap_or_default()
+                ),
+                escape_html(&serde_json::to_string_pretty(entry).unwrap_or_default())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    page(
+        "Approve access",
+        &format!(
+            "<p>{} asks for permission to:</p>\n{entries}\n\
+             <form method=\"post\" action=\"/authorize/consent\">\n\
+             <input type=\"hidden\" name=\"consent_id\" value=\"{}\">\n\
+             <button name=\"action\" value=\"approve\">Approve</button>\n\
+             <button name=\"action\" value=\"deny\">Deny</button>\n\
+             </form>",
+            escape_html(&pending.client_id),
+            escape_html(&pending.consent_id)
+        ),
+    )
+    .into_response()
+}
+
+/*
+* POST /authorize/consent
+    consent_id=...&action=approve|deny
+
+    Content-Type: application/x-www-form-urlencoded
+
+* OUTPUT
+* same as GET /authorize once approved
+* 302 Location: ${redirect_uri}?error=access_denied&error_description=...&state=STATE when denied
+
+* VALIDATE
+* the consent exists, has not expired or been answered, and belongs to the logged-in user
+*/
+
+#[axum::debug_handler]
+pub async fn consent(
+    State(app): State<AppState>,
+    jar: CookieJar,
+    cf: Result<Form<ConsentForm>, FormRejection>,
+) -> impl IntoResponse {
+    let Ok(Form(cf)) = cf else {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "Invalid request",
+            "The form could not be read.",
+        );
+    };
+
+    let session = match jar.get(COOKIE_NAME) {
+        Some(c) => get_session(&app, c.value()).await,
+        None => Ok(None),
+    };
+    let session = match session {
+        Ok(Some(session)) => session,
+        Ok(None) => return login_required(),
         Err(err) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "server_error", "detail": err.to_string() })),
-            ).into_response();
+            return server_error(&err);
         }
     };
 
-    match authorize_svc(
+    match complete_consent(
         &app,
-        AuthorizeInput {
-            client_id: client_id.to_string(),
-            redirect_uri,
-            scopes,
-            user_id: email,
-            state: aq.state,
-        },
+        &cf.consent_id,
+        &session.user_id,
+        cf.action == "approve",
     )
     .await
     {
-        Ok(res) => (
-            StatusCode::FOUND,
-            Json(AuthorizeResponse {
-                redirect_to: format!("{}?code={}", res.redirect_uri, res.code),
-                code: res.code,
-                state: if res.state.is_empty() {
-                    None
-                } else {
-                    Some(res.state)
-                },
-            }),
-        )
-            .into_response(),
-        Err(err) => (
+        Ok(Some(ConsentOutcome::Approved(res))) => code_redirect(&app, &res).await,
+        Ok(Some(ConsentOutcome::Denied(redirect_to))) => error_redirect(&app, &redirect_to).await,
+        Ok(None) => error_page(
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "authorization_failed", "detail": err.to_string() })),
-        ).into_response(),
+            "Invalid request",
+            "The consent is unknown or has expired.",
+        ),
+        Err(err) => server_error(&err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(value: serde_json::Value) -> AuthorizationParams {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn a_request_needs_a_redirect_uri() {
+        let without = params(json!({ "response_type": "code", "scope": "read" }));
+        assert!(without.into_request("client".to_string(), false).is_none());
+    }
+
+    #[test]
+    fn scope_is_split_into_scopes() {
+        let request = params(json!({
+            "redirect_uri": "https://client.example/cb",
+            "scope": "read  write",
+            "state": "xyz"
+        }))
+        .into_request("client".to_string(), true)
+        .unwrap();
+        assert_eq!(request.client_id, "client");
+        assert_eq!(request.redirect_uri, "https://client.example/cb");
+        assert_eq!(request.scopes, ["read", "write"]);
+        assert_eq!(request.state.as_deref(), Some("xyz"));
+        assert!(request.signed);
+    }
+
+    fn request_object(value: serde_json::Value) -> RequestObject {
+        serde_json::from_value(value).unwrap()
+    }
+
+    // RFC 9101 section 6.3
+    #[test]
+    fn request_object_claims_win_over_parameters() {
+        let mut merged = params(json!({
+            "redirect_uri": "https://client.example/query",
+            "scope": "read",
+            "state": "from-query"
+        }));
+        merged.merge(request_object(json!({
+            "redirect_uri": "https://client.example/signed",
+            "scope": "write",
+            "authorization_details": [{ "type": "payment_initiation" }]
+        })));
+
+        let request = merged.into_request("client".to_string(), true).unwrap();
+        assert_eq!(request.redirect_uri, "https://client.example/signed");
+        assert_eq!(request.scopes, ["write"]);
+        // what the request object leaves out still comes from the parameters
+        assert_eq!(request.state.as_deref(), Some("from-query"));
+        assert_eq!(
+            request.authorization_details.as_deref(),
+            Some(r#"[{"type":"payment_initiation"}]"#)
+        );
+    }
+
+    #[test]
+    fn an_empty_request_object_changes_nothing() {
+        let mut merged = params(json!({
+            "redirect_uri": "https://client.example/cb",
+            "code_challenge": "challenge",
+            "code_challenge_method": "S256"
+        }));
+        merged.merge(request_object(json!({})));
+
+        let request = merged.into_request("client".to_string(), true).unwrap();
+        assert_eq!(request.redirect_uri, "https://client.example/cb");
+        assert_eq!(request.code_challenge.as_deref(), Some("challenge"));
+        assert_eq!(request.code_challenge_method.as_deref(), Some("S256"));
     }
 }
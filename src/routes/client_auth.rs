@@ -0,0 +1,332 @@
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::json;
+use url::form_urlencoded;
+
+use crate::services::client_assertion::{assertion_subject, CLIENT_ASSERTION_TYPE};
+use crate::services::mtls::ClientCertificate;
+use crate::services::token::ClientAuth;
+
+// Parameters that must never show up in a URL (and so in access logs)
+static QUERY_CREDENTIALS: [&str; 3] = ["client_secret", "client_id", "client_assertion"];
+
+// Client authentication fields, flattened into the form of every endpoint that authenticates clients
+// field names are the RFC parameter names
+#[allow(clippy::struct_field_names)]
+#[derive(Deserialize, Default)]
+pub struct ClientAuthForm {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+}
+
+pub struct ClientCredentials {
+    pub client_id: String,
+    pub auth: ClientAuth,
+}
+
+pub enum ClientAuthError {
+    InvalidRequest(&'static str),
+    InvalidClient(&'static str),
+}
+
+impl IntoResponse for ClientAuthError {
+    fn into_response(self) -> Response {
+        match self {
+            ClientAuthError::InvalidRequest(detail) => (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_request", "detail": detail })),
+            )
+                .into_response(),
+            // RFC 6749 section 5.2: tell the client which scheme to use
+            ClientAuthError::InvalidClient(detail) => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Basic realm=\"loom\"")],
+                Json(json!({ "error": "invalid_client", "detail": detail })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+// Basic credentials are form-urlencoded before base64 (RFC 6749 section 2.3.1)
+fn form_decode(value: &str) -> Option<String> {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8()
+        .ok()
+        .map(std::borrow::Cow::into_owned)
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Result<Option<ClientCredentials>, ClientAuthError> {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+
+    let malformed = ClientAuthError::InvalidClient("malformed Basic authorization header");
+    let Some(encoded) = value.to_str().ok().and_then(|v| v.strip_prefix("Basic ")) else {
+        return Err(ClientAuthError::InvalidClient(
+            "only Basic authorization is supported",
+        ));
+    };
+    let Some(decoded) = STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|d| String::from_utf8(d).ok())
+    else {
+        return Err(malformed);
+    };
+    let Some((id, secret)) = decoded.split_once(':') else {
+        return Err(malformed);
+    };
+
+    match (form_decode(id), form_decode(secret)) {
+        (Some(client_id), Some(client_secret)) => Ok(Some(ClientCredentials {
+            client_id,
+            auth: ClientAuth::Secret(client_secret),
+        })),
+        _ => Err(malformed),
+    }
+}
+
+// private_key_jwt (RFC 7523 section 2.2), the client_id may be left out of the form
+fn assertion_credentials(
+    client_id: Option<String>,
+    assertion_type: Option<&str>,
+    assertion: String,
+) -> Result<ClientCredentials, ClientAuthError> {
+    if assertion_type != Some(CLIENT_ASSERTION_TYPE) {
+        return Err(ClientAuthError::InvalidRequest(
+            "client_assertion_type must be urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+        ));
+    }
+
+    let Some(client_id) = client_id.or_else(|| assertion_subject(&assertion)) else {
+        return Err(ClientAuthError::InvalidClient(
+            "client_assertion does not name a client",
+        ));
+    };
+
+    Ok(ClientCredentials {
+        client_id,
+        auth: ClientAuth::Assertion(assertion),
+    })
+}
+
+/*
+ * Resolves client_secret_basic (Authorization header), client_secret_post (form body),
+ * private_key_jwt (client_assertion in the form body) or, when none of those is used,
+ * tls_client_auth / self_signed_tls_client_auth (client_id in the form body plus the
+ * certificate from the TLS handshake).
+ * A client must use exactly one method, and nothing may be sent in the query string.
+ */
+pub fn client_credentials(
+    headers: &HeaderMap,
+    uri: &Uri,
+    form: ClientAuthForm,
+    certificate: Option<ClientCertificate>,
+) -> Result<ClientCredentials, ClientAuthError> {
+    // decoded like the extractors decode it, `client%5Fsecret` is still client_secret
+    let in_query = form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+        .any(|(key, _)| QUERY_CREDENTIALS.contains(&key.as_ref()));
+    if in_query {
+        return Err(ClientAuthError::InvalidRequest(
+            "client credentials must be sent in the request body or Authorization header, not the URL",
+        ));
+    }
+
+    let basic = basic_credentials(headers)?;
+    let methods = usize::from(basic.is_some())
+        + usize::from(form.client_secret.is_some())
+        + usize::from(form.client_assertion.is_some());
+    if methods > 1 {
+        return Err(ClientAuthError::InvalidRequest(
+            "use exactly one client authentication method",
+        ));
+    }
+
+    if let Some(assertion) = form.client_assertion {
+        return assertion_credentials(
+            form.client_id,
+            form.client_assertion_type.as_deref(),
+            assertion,
+        );
+    }
+
+    match (basic, form.client_id, form.client_secret, certificate) {
+        (Some(basic), Some(form_id), _, _) if form_id != basic.client_id => Err(
+            ClientAuthError::InvalidRequest("client_id does not match the Authorization header"),
+        ),
+        (Some(basic), _, _, _) => Ok(basic),
+        (None, Some(client_id), Some(client_secret), _) => Ok(ClientCredentials {
+            client_id,
+            auth: ClientAuth::Secret(client_secret),
+        }),
+        (None, Some(client_id), None, Some(certificate)) => Ok(ClientCredentials {
+            client_id,
+            auth: ClientAuth::Certificate(certificate),
+        }),
+        (None, _, _, _) => Err(ClientAuthError::InvalidClient(
+            "client authentication is required",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn basic(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", STANDARD.encode(credentials));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&value).unwrap(),
+        );
+        headers
+    }
+
+    fn form(client_id: Option<&str>, client_secret: Option<&str>) -> ClientAuthForm {
+        ClientAuthForm {
+            client_id: client_id.map(str::to_string),
+            client_secret: client_secret.map(str::to_string),
+            ..ClientAuthForm::default()
+        }
+    }
+
+    fn token_uri(query: &str) -> Uri {
+        format!("/token{query}").parse().unwrap()
+    }
+
+    #[test]
+    fn basic_credentials_are_form_decoded() {
+        let credentials = client_credentials(
+            &basic("my%20client:s%3Acret+x"),
+            &token_uri(""),
+            ClientAuthForm::default(),
+            None,
+        );
+        let Ok(ClientCredentials {
+            client_id,
+            auth: ClientAuth::Secret(secret),
+        }) = credentials
+        else {
+            panic!("expected client_secret_basic credentials");
+        };
+        assert_eq!(client_id, "my client");
+        assert_eq!(secret, "s:cret x");
+    }
+
+    #[test]
+    fn post_credentials_are_accepted() {
+        let credentials = client_credentials(
+            &HeaderMap::new(),
+            &token_uri(""),
+            form(Some("client"), Some("secret")),
+            None,
+        );
+        assert!(matches!(
+            credentials,
+            Ok(ClientCredentials {
+                auth: ClientAuth::Secret(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn basic_and_post_together_are_rejected() {
+        let credentials = client_credentials(
+            &basic("client:secret"),
+            &token_uri(""),
+            form(Some("client"), Some("secret")),
+            None,
+        );
+        assert!(matches!(
+            credentials,
+            Err(ClientAuthError::InvalidRequest(
+                "use exactly one client authentication method"
+            ))
+        ));
+    }
+
+    #[test]
+    fn secret_and_assertion_together_are_rejected() {
+        let credentials = client_credentials(
+            &HeaderMap::new(),
+            &token_uri(""),
+            ClientAuthForm {
+                client_assertion_type: Some(CLIENT_ASSERTION_TYPE.to_string()),
+                client_assertion: Some("a.b.c".to_string()),
+                ..form(Some("client"), Some("secret"))
+            },
+            None,
+        );
+        assert!(matches!(
+            credentials,
+            Err(ClientAuthError::InvalidRequest(
+                "use exactly one client authentication method"
+            ))
+        ));
+    }
+
+    #[test]
+    fn credentials_in_the_query_are_rejected() {
+        for query in [
+            "?client_secret=secret",
+            "?client%5Fsecret=secret",
+            "?client_id=client",
+        ] {
+            let credentials = client_credentials(
+                &HeaderMap::new(),
+                &token_uri(query),
+                form(Some("client"), Some("secret")),
+                None,
+            );
+            assert!(
+                matches!(credentials, Err(ClientAuthError::InvalidRequest(_))),
+                "{query} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn a_different_form_client_id_is_rejected() {
+        let credentials = client_credentials(
+            &basic("client:secret"),
+            &token_uri(""),
+            form(Some("other"), None),
+            None,
+        );
+        assert!(matches!(
+            credentials,
+            Err(ClientAuthError::InvalidRequest(
+                "client_id does not match the Authorization header"
+            ))
+        ));
+    }
+
+    #[test]
+    fn a_client_id_alone_is_not_authentication() {
+        let credentials = client_credentials(
+            &HeaderMap::new(),
+            &token_uri(""),
+            form(Some("client"), None),
+            None,
+        );
+        assert!(matches!(
+            credentials,
+            Err(ClientAuthError::InvalidClient(
+                "client authentication is required"
+            ))
+        ));
+    }
+}
@@ -8,7 +8,13 @@ use axum::{
 use serde::Deserialize;
 use tracing::info;
 
+use crate::repositories::clients::{
+    AccessTokenFormat, ClientSettings, LifetimeOverrides, ResponseMode, TokenEndpointAuthMethod,
+};
+use crate::services::authorization_details::check_authorization_details_types;
 use crate::services::client::register_client_service;
+use crate::services::jarm::{encryption_key, ENCRYPTION_ALG, ENCRYPTION_ENC};
+use crate::services::lifetimes::MAX_ACCESS_TOKEN_LIFETIME_SECS;
 
 #[derive(Deserialize, Debug)]
 pub struct NewClientRequest {
@@ -16,6 +22,176 @@ pub struct NewClientRequest {
     redirect_uris: Vec<String>,
     grant_types: Vec<String>,
     scopes: Vec<String>,
+    #[serde(default)]
+    require_pkce: bool,
+    // RFC 9126: /authorize only accepts request_uri values from /par
+    #[serde(default)]
+    require_pushed_authorization_requests: bool,
+    // RFC 9101: /authorize only accepts request objects signed with the client's jwks
+    #[serde(default)]
+    require_signed_request_object: bool,
+    token_endpoint_auth_method: Option<String>,
+    // private_key_jwt / self_signed_tls_client_auth: exactly one of these (RFC 7591 section 2)
+    jwks: Option<serde_json::Value>,
+    jwks_uri: Option<String>,
+    // tls_client_auth (RFC 8705 section 2.1.2)
+    tls_client_auth_subject_dn: Option<String>,
+    // "jwt" (default) or "opaque"
+    access_token_format: Option<String>,
+    // seconds, the server defaults apply to whatever is left out
+    access_token_lifetime_secs: Option<u64>,
+    refresh_token_idle_lifetime_secs: Option<u64>,
+    refresh_token_absolute_lifetime_secs: Option<u64>,
+    auth_code_lifetime_secs: Option<u64>,
+    session_lifetime_secs: Option<u64>,
+    // RFC 9396: { "<type>": <JSON Schema>, ... }, the authorization_details the client may request
+    authorization_details_types: Option<serde_json::Value>,
+    // query (default), fragment, form_post, web_message, jwt, query.jwt, fragment.jwt, form_post.jwt
+    response_modes: Option<Vec<String>>,
+    // JARM: encrypt JWT authorization responses to the client's RSA key in jwks / jwks_uri
+    authorization_encrypted_response_alg: Option<String>,
+    authorization_encrypted_response_enc: Option<String>,
+}
+
+impl NewClientRequest {
+    // What the auth method and request objects need to verify the client: a JWKS or a certificate subject
+    fn check_key_metadata(
+        &self,
+        auth_method: TokenEndpointAuthMethod,
+        jwks: Option<&str>,
+    ) -> Result<(), String> {
+        if jwks.is_some_and(|jwks| serde_json::from_str::<jsonwebtoken::jwk::JwkSet>(jwks).is_err())
+        {
+            return Err("jwks is not a valid JWK Set".to_string());
+        }
+
+        let uses_jwks = matches!(
+            auth_method,
+            TokenEndpointAuthMethod::PrivateKeyJwt
+                | TokenEndpointAuthMethod::SelfSignedTlsClientAuth
+        );
+        // whatever the keys are for, they come from one place and never over plaintext
+        match (jwks, &self.jwks_uri) {
+            (Some(_), Some(_)) => {
+                return Err("jwks and jwks_uri must not both be present".to_string());
+            }
+            (_, Some(uri)) if !uri.starts_with("https://") => {
+                return Err("jwks_uri must use https".to_string());
+            }
+            _ => {}
+        }
+
+        let has_keys = jwks.is_some() || self.jwks_uri.is_some();
+        if uses_jwks && !has_keys {
+            return Err(format!(
+                "{} requires jwks or jwks_uri",
+                auth_method.as_str()
+            ));
+        }
+        if self.require_signed_request_object && !has_keys {
+            return Err("require_signed_request_object requires jwks or jwks_uri".to_string());
+        }
+        if self.authorization_encrypted_response_alg.is_some() {
+            if !has_keys {
+                return Err(
+                    "authorization_encrypted_response_alg requires jwks or jwks_uri".to_string(),
+                );
+            }
+            // a jwks_uri is only checked once Loom encrypts to it
+            if jwks.is_some_and(|jwks| {
+                serde_json::from_str::<jsonwebtoken::jwk::JwkSet>(jwks)
+                    .is_ok_and(|jwks| encryption_key(&jwks).is_none())
+            }) {
+                return Err("authorization_encrypted_response_alg requires an RSA key in jwks that is not only for signing".to_string());
+            }
+        }
+
+        if auth_method == TokenEndpointAuthMethod::TlsClientAuth
+            && self.tls_client_auth_subject_dn.is_none()
+        {
+            return Err("tls_client_auth requires tls_client_auth_subject_dn".to_string());
+        }
+        Ok(())
+    }
+
+    fn lifetimes(&self) -> Result<LifetimeOverrides, String> {
+        let lifetimes = LifetimeOverrides {
+            access_token: self.access_token_lifetime_secs,
+            refresh_token_idle: self.refresh_token_idle_lifetime_secs,
+            refresh_token_absolute: self.refresh_token_absolute_lifetime_secs,
+            auth_code: self.auth_code_lifetime_secs,
+            session: self.session_lifetime_secs,
+        };
+        let all = [
+            lifetimes.access_token,
+            lifetimes.refresh_token_idle,
+            lifetimes.refresh_token_absolute,
+            lifetimes.auth_code,
+            lifetimes.session,
+        ];
+        if all.contains(&Some(0)) {
+            return Err("lifetimes must be a positive number of seconds".to_string());
+        }
+        if lifetimes
+            .access_token
+            .is_some_and(|secs| secs > MAX_ACCESS_TOKEN_LIFETIME_SECS)
+        {
+            return Err(format!(
+                "access_token_lifetime_secs must not exceed {MAX_ACCESS_TOKEN_LIFETIME_SECS}"
+            ));
+        }
+        Ok(lifetimes)
+    }
+
+    fn response_modes(&self) -> Option<Vec<ResponseMode>> {
+        match &self.response_modes {
+            Some(modes) if !modes.is_empty() => {
+                modes.iter().map(|mode| ResponseMode::parse(mode)).collect()
+            }
+            Some(_) => None,
+            None => Some(vec![ResponseMode::default()]),
+        }
+    }
+
+    // RFC 9396: every registered schema must compile, stored as the JSON it was sent as
+    fn authorization_details_types(&self) -> Result<Option<String>, String> {
+        let Some(types) = &self.authorization_details_types else {
+            return Ok(None);
+        };
+        check_authorization_details_types(types)?;
+        Ok(Some(types.to_string()))
+    }
+
+    // JARM section 3: enc defaults to A256GCM once alg is given, and means nothing without it
+    fn authorization_response_encryption(
+        &self,
+    ) -> Result<(Option<String>, Option<String>), String> {
+        match (
+            self.authorization_encrypted_response_alg.as_deref(),
+            self.authorization_encrypted_response_enc.as_deref(),
+        ) {
+            (None, None) => Ok((None, None)),
+            (None, Some(_)) => Err(
+                "authorization_encrypted_response_enc requires authorization_encrypted_response_alg"
+                    .to_string(),
+            ),
+            (Some(alg), _) if alg != ENCRYPTION_ALG => Err(format!(
+                "authorization_encrypted_response_alg must be {ENCRYPTION_ALG}"
+            )),
+            (Some(_), Some(enc)) if enc != ENCRYPTION_ENC => Err(format!(
+                "authorization_encrypted_response_enc must be {ENCRYPTION_ENC}"
+            )),
+            (Some(alg), _) => Ok((Some(alg.to_string()), Some(ENCRYPTION_ENC.to_string()))),
+        }
+    }
+}
+
+fn invalid_metadata(detail: &str) -> axum::response::Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({ "error": "invalid_client_metadata", "detail": detail })),
+    )
+        .into_response()
 }
 
 #[axum::debug_handler]
@@ -34,23 +210,91 @@ pub async fn register_client(
         }
     };
 
+    let Some(auth_method) = new_client.token_endpoint_auth_method.as_deref().map_or(
+        Some(TokenEndpointAuthMethod::default()),
+        TokenEndpointAuthMethod::parse,
+    ) else {
+        return invalid_metadata("unsupported token_endpoint_auth_method");
+    };
+
+    let Some(access_token_format) = new_client
+        .access_token_format
+        .as_deref()
+        .map_or(Some(AccessTokenFormat::default()), AccessTokenFormat::parse)
+    else {
+        return invalid_metadata("access_token_format must be jwt or opaque");
+    };
+
+    let jwks = new_client.jwks.as_ref().map(ToString::to_string);
+    if let Err(detail) = new_client.check_key_metadata(auth_method, jwks.as_deref()) {
+        return invalid_metadata(&detail);
+    }
+
+    let lifetimes = match new_client.lifetimes() {
+        Ok(lifetimes) => lifetimes,
+        Err(detail) => return invalid_metadata(&detail),
+    };
+
+    let Some(response_modes) = new_client.response_modes() else {
+        return invalid_metadata("response_modes must list query, fragment, form_post, web_message or their .jwt variants");
+    };
+
+    let (encrypted_response_alg, encrypted_response_enc) =
+        match new_client.authorization_response_encryption() {
+            Ok(encryption) => encryption,
+            Err(detail) => return invalid_metadata(&detail),
+        };
+
+    let authorization_details_types = match new_client.authorization_details_types() {
+        Ok(types) => types,
+        Err(detail) => return invalid_metadata(&detail),
+    };
+
     match register_client_service(
         &appstate,
         new_client.client_name.as_str(),
         &new_client.redirect_uris,
         &new_client.grant_types,
         &new_client.scopes,
+        &ClientSettings {
+            require_pkce: new_client.require_pkce,
+            require_pushed_authorization_requests: new_client.require_pushed_authorization_requests,
+            require_signed_request_object: new_client.require_signed_request_object,
+            token_endpoint_auth_method: auth_method,
+            jwks,
+            jwks_uri: new_client.jwks_uri.clone(),
+            tls_client_auth_subject_dn: new_client.tls_client_auth_subject_dn.clone(),
+            access_token_format,
+            lifetimes,
+            authorization_details_types,
+            response_modes: response_modes.clone(),
+            authorization_encrypted_response_alg: encrypted_response_alg.clone(),
+            authorization_encrypted_response_enc: encrypted_response_enc.clone(),
+        },
     )
     .await
     {
         Ok((client_id, secret_plain)) => {
             info!("Registered new client with ID: {}", client_id);
+            let effective = appstate.lifetimes().for_client(&lifetimes);
             (
                 StatusCode::CREATED,
                 Json(serde_json::json!({ 
                   "status": "success",
                   "client_id": client_id,
                   "client_name": new_client.client_name,
+                  "token_endpoint_auth_method": auth_method.as_str(),
+                  "access_token_format": access_token_format.as_str(),
+                  "require_pushed_authorization_requests": new_client.require_pushed_authorization_requests,
+                  "require_signed_request_object": new_client.require_signed_request_object,
+                  "response_modes": response_modes.iter().map(|mode| mode.as_str()).collect::<Vec<_>>(),
+                  "authorization_encrypted_response_alg": encrypted_response_alg,
+                  "authorization_encrypted_response_enc": encrypted_response_enc,
+                  "access_token_lifetime_secs": effective.access_token,
+                  "refresh_token_idle_lifetime_secs": effective.refresh_token_idle,
+                  "refresh_token_absolute_lifetime_secs": effective.refresh_token_absolute,
+                  "auth_code_lifetime_secs": effective.auth_code,
+                  "session_lifetime_secs": effective.session,
                   "client_secret": secret_plain })),
             )
                 .into_response()
@@ -0,0 +1,273 @@
+use axum::{
+    extract::{rejection::FormRejection, rejection::QueryRejection, Extension, Form, Query, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::IntoResponse,
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::routes::client_auth::{client_credentials, ClientAuthForm};
+use crate::routes::html::{escape_html, login_required, page};
+use crate::routes::COOKIE_NAME;
+use crate::services::device::{
+    complete_device_authorization, format_user_code, normalize_user_code,
+    start_device_authorization, DEVICE_CODE_GRANT_TYPE,
+};
+use crate::services::mtls::ClientCertificate;
+use crate::services::token::{authenticate_client, grant_scopes};
+use crate::services::user::get_session;
+use crate::services::TokenInput;
+use crate::state::AppState;
+
+#[derive(Deserialize)]
+pub struct DeviceAuthorizationForm {
+    scope: Option<String>,
+    #[serde(flatten)]
+    client_auth: ClientAuthForm,
+}
+
+#[derive(Serialize)]
+struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+#[derive(Deserialize)]
+pub struct VerificationQuery {
+    user_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerificationForm {
+    user_code: String,
+    action: String,
+}
+
+/*
+* POST /device_authorization
+    client_id=...
+    &client_secret=...
+    &scope=...
+
+    Content-Type: application/x-www-form-urlencoded
+    client authentication as for POST /token
+
+* OUTPUT
+* 200 { "device_code": "...", "user_code": "BCDF-GHJK", "verification_uri": "{BASE_URL}/device",
+*       "verification_uri_complete": "{BASE_URL}/device?user_code=BCDF-GHJK", "expires_in": 600, "interval": 5 }
+
+* VALIDATE
+* client authenticates with its registered method
+* client has the device_code grant registered, requested scopes are registered for it
+
+* CORE LOGIC
+* Store a pending device code in redis, the device then polls /token with it
+* while the user approves the user code at /device
+*/
+
+#[axum::debug_handler]
+pub async fn device_authorization(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    certificate: Option<Extension<ClientCertificate>>,
+    df: Result<Form<DeviceAuthorizationForm>, FormRejection>,
+) -> impl IntoResponse {
+    let mut df = match df {
+        Ok(Form(df)) => df,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_request", "detail": err.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    let creds = match client_credentials(
+        &headers,
+        &uri,
+        std::mem::take(&mut df.client_auth),
+        certificate.map(|Extension(c)| c),
+    ) {
+        Ok(creds) => creds,
+        Err(err) => return err.into_response(),
+    };
+
+    let client = match authenticate_client(
+        &app,
+        &TokenInput {
+            client_id: creds.client_id.clone(),
+            redirect_uri: None,
+        },
+        &creds.auth,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "invalid_client", "detail": e.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    if !client.allows_grant(DEVICE_CODE_GRANT_TYPE) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "unauthorized_client", "detail": "client is not allowed to use the device_code grant" })),
+        )
+            .into_response();
+    }
+
+    let Some(scope) = grant_scopes(
+        df.scope.as_deref(),
+        client.scopes.as_deref().unwrap_or_default(),
+    ) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_scope", "detail": "requested scope is not registered for this client" })),
+        )
+            .into_response();
+    };
+
+    let scopes = scope.split_whitespace().map(str::to_string).collect();
+    match start_device_authorization(&app, &creds.client_id, scopes).await {
+        Ok(auth) => {
+            let user_code = format_user_code(&auth.user_code);
+            let verification_uri = format!("{}/device", app.base_url());
+            Json(DeviceAuthorizationResponse {
+                device_code: auth.device_code,
+                verification_uri_complete: format!("{verification_uri}?user_code={user_code}"),
+                verification_uri,
+                user_code,
+                expires_in: auth.expires_in,
+                interval: auth.interval,
+            })
+            .into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "server_error", "detail": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+async fn session_user(app: &AppState, jar: &CookieJar) -> anyhow::Result<Option<String>> {
+    match jar.get(COOKIE_NAME) {
+        Some(c) => Ok(get_session(app, c.value()).await?.map(|s| s.user_id)),
+        None => Ok(None),
+    }
+}
+
+/*
+* GET /device?user_code=...
+* Verification page: the logged-in user confirms the code shown on their device
+*/
+
+#[axum::debug_handler]
+pub async fn device_page(
+    State(app): State<AppState>,
+    jar: CookieJar,
+    vq: Result<Query<VerificationQuery>, QueryRejection>,
+) -> impl IntoResponse {
+    let user_code = vq
+        .ok()
+        .and_then(|Query(vq)| vq.user_code)
+        .unwrap_or_default();
+
+    match session_user(&app, &jar).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return login_required(),
+        Err(err) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                page("Something went wrong", &escape_html(&err.to_string())),
+            )
+                .into_response();
+        }
+    }
+
+    page(
+        "Connect a device",
+        &format!(
+            "<p>Enter the code shown on your device.</p>\n\
+             <form method=\"post\" action=\"/device\">\n\
+             <input name=\"user_code\" value=\"{}\" autocomplete=\"off\" required>\n\
+             <button name=\"action\" value=\"approve\">Approve</button>\n\
+             <button name=\"action\" value=\"deny\">Deny</button>\n\
+             </form>",
+            escape_html(&user_code)
+        ),
+    )
+    .into_response()
+}
+
+/*
+* POST /device
+    user_code=...&action=approve|deny
+
+    Content-Type: application/x-www-form-urlencoded
+*/
+
+#[axum::debug_handler]
+pub async fn device_confirm(
+    State(app): State<AppState>,
+    jar: CookieJar,
+    vf: Result<Form<VerificationForm>, FormRejection>,
+) -> impl IntoResponse {
+    let Ok(Form(vf)) = vf else {
+        return (
+            StatusCode::BAD_REQUEST,
+            page("Invalid request", "<p>The form could not be read.</p>"),
+        )
+            .into_response();
+    };
+
+    let user_id = match session_user(&app, &jar).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return login_required(),
+        Err(err) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                page("Something went wrong", &escape_html(&err.to_string())),
+            )
+                .into_response();
+        }
+    };
+
+    let approve = vf.action == "approve";
+    match complete_device_authorization(&app, &vf.user_code, &user_id, approve).await {
+        Ok(true) if approve => {
+            page("Device connected", "<p>You can return to your device.</p>").into_response()
+        }
+        Ok(true) => {
+            page("Request denied", "<p>The device was not given access.</p>").into_response()
+        }
+        Ok(false) => (
+            StatusCode::BAD_REQUEST,
+            page(
+                "Invalid code",
+                &format!(
+                    "<p>The code {} is unknown, expired or was already used.</p>",
+                    escape_html(&format_user_code(&normalize_user_code(&vf.user_code)))
+                ),
+            ),
+        )
+            .into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            page("Something went wrong", &escape_html(&err.to_string())),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,41 @@
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+
+// Minimal escaping for values interpolated into server-rendered pages
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// `body` must already be escaped
+pub fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>\n{body}\n</body>\n</html>\n",
+        escape_html(title),
+        escape_html(title),
+    ))
+}
+
+// Pages that need a logged-in user, Loom has no login page to send the browser to
+pub fn login_required() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        page(
+            "Sign in required",
+            "<p>Please sign in to Loom, then open this page again.</p>",
+        ),
+    )
+        .into_response()
+}
@@ -0,0 +1,113 @@
+use axum::{
+    extract::{rejection::FormRejection, Extension, Form, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::routes::client_auth::{client_credentials, ClientAuthForm};
+use crate::services::introspect::introspect as introspect_token;
+use crate::services::mtls::ClientCertificate;
+use crate::services::token::authenticate_client;
+use crate::services::TokenInput;
+use crate::state::AppState;
+
+#[derive(Deserialize)]
+pub struct IntrospectForm {
+    token: String,
+    token_type_hint: Option<String>,
+    #[serde(flatten)]
+    client_auth: ClientAuthForm,
+}
+
+/*
+* POST /introspect
+    token=...
+    &token_type_hint=access_token|refresh_token
+    &client_id=...
+    &client_secret=...
+
+    Content-Type: application/x-www-form-urlencoded
+    client authentication as for POST /token
+
+* OUTPUT
+* 200 { "active": true, "scope": "...", "client_id": "...", "sub": "...", "exp": 1700000000, "iat": 1699996400, "token_type": "Bearer" }
+*     access tokens also report "nbf", "aud", "iss" and "jti", both kinds "authorization_details" when granted
+* 200 { "active": false }
+
+* VALIDATE
+* client authenticates with its registered method
+* access token: typ at+jwt, signed by a published key, issued by this server, not expired
+* refresh token: issued to the calling client, not expired, rotated or revoked
+
+* CORE LOGIC
+* Report whether the token is active and what it grants
+*/
+
+#[axum::debug_handler]
+pub async fn introspect(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    certificate: Option<Extension<ClientCertificate>>,
+    form: Result<Form<IntrospectForm>, FormRejection>,
+) -> impl IntoResponse {
+    let mut form = match form {
+        Ok(Form(form)) => form,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_request", "detail": err.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    let creds = match client_credentials(
+        &headers,
+        &uri,
+        std::mem::take(&mut form.client_auth),
+        certificate.map(|Extension(c)| c),
+    ) {
+        Ok(creds) => creds,
+        Err(err) => return err.into_response(),
+    };
+
+    let client = match authenticate_client(
+        &app,
+        &TokenInput {
+            client_id: creds.client_id.clone(),
+            redirect_uri: None,
+        },
+        &creds.auth,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "invalid_client", "detail": e.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    match introspect_token(
+        &app,
+        client.id,
+        &form.token,
+        form.token_type_hint.as_deref(),
+    )
+    .await
+    {
+        Ok(introspection) => Json(introspection).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "server_error", "detail": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
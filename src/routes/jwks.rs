@@ -0,0 +1,40 @@
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+
+use crate::services::keys::{published_jwks, JWKS_MAX_AGE_SECS};
+use crate::state::AppState;
+
+/*
+* GET /.well-known/jwks.json
+
+* OUTPUT
+* 200 { "keys": [ { "kty": "RSA", "use": "sig", "alg": "RS256", "kid": "...", "n": "...", "e": "AQAB" } ] }
+
+* CORE LOGIC
+* Publish the public half of every pending and active key, and of retired keys
+* until the access tokens they signed have expired
+*/
+
+#[axum::debug_handler]
+pub async fn jwks(State(app): State<AppState>) -> impl IntoResponse {
+    match published_jwks(&app) {
+        Ok(jwks) => (
+            [(
+                header::CACHE_CONTROL,
+                format!("public, max-age={JWKS_MAX_AGE_SECS}"),
+            )],
+            Json(jwks),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "server_error", "detail": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
@@ -6,9 +6,17 @@ use axum::{
 use crate::state::AppState;
 
 mod authorize;
+mod client_auth;
 mod clients;
+mod device;
 mod echo;
 mod health;
+mod html;
+mod introspect;
+mod jwks;
+mod par;
+mod resources;
+mod revoke;
 mod token;
 mod user;
 
@@ -17,10 +25,21 @@ pub const COOKIE_NAME: &str = "session_id";
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/health", get(health::health_check))
+        .route("/.well-known/jwks.json", get(jwks::jwks))
         .route("/echo", post(echo::echo))
         .route("/token", post(token::token))
+        .route("/introspect", post(introspect::introspect))
+        .route("/revoke", post(revoke::revoke))
         .route("/authorize", get(authorize::authorize))
+        .route("/authorize/consent", post(authorize::consent))
+        .route("/par", post(par::pushed_authorization_request))
         .route("/register", post(user::register_user))
         .route("/clients", post(clients::register_client))
+        .route("/resources", post(resources::register_resource))
         .route("/login", post(user::login))
+        .route("/device_authorization", post(device::device_authorization))
+        .route(
+            "/device",
+            get(device::device_page).post(device::device_confirm),
+        )
 }
@@ -0,0 +1,167 @@
+use axum::{
+    extract::{rejection::FormRejection, Extension, Form, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::routes::authorize::AuthorizationParams;
+use crate::routes::client_auth::{client_credentials, ClientAuthForm};
+use crate::services::authorize::{push_authorization_request, PUSHED_REQUEST_EXPIRATION_SECS};
+use crate::services::mtls::ClientCertificate;
+use crate::services::token::authenticate_client;
+use crate::services::TokenInput;
+use crate::state::AppState;
+
+#[derive(Deserialize)]
+pub struct PushedAuthorizationForm {
+    // not allowed here (RFC 9126 section 2.1)
+    request_uri: Option<String>,
+    // RFC 9126 section 3: a signed request object can be pushed as well
+    request: Option<String>,
+    #[serde(flatten)]
+    params: AuthorizationParams,
+    #[serde(flatten)]
+    client_auth: ClientAuthForm,
+}
+
+#[derive(Serialize)]
+struct PushedAuthorizationResponse {
+    request_uri: String,
+    expires_in: u64,
+}
+
+/*
+* POST /par
+    response_type=code
+    &response_mode=...
+    &redirect_uri=...
+    &scope=...
+    &state=...
+    &code_challenge=...
+    &code_challenge_method=S256|plain
+    &resource=...
+    &authorization_details=...
+    &request=<signed JWT> (optional, RFC 9101 request object, its claims win over the parameters)
+    &client_id=...
+    &client_secret=...
+
+    Content-Type: application/x-www-form-urlencoded
+    client authentication as for POST /token
+
+* OUTPUT
+* 201 { "request_uri": "urn:ietf:params:oauth:request_uri:...", "expires_in": 60 }
+* the browser is then sent to GET /authorize?client_id=...&request_uri=...
+
+* VALIDATE
+* client authentication as for POST /token
+* the same checks as GET /authorize makes before the user is involved:
+* response_type, redirect_uri and scopes registered, request object, PKCE, resource, authorization_details
+
+* CORE LOGIC
+* Store the validated request in redis under a random request_uri, one-time use, expires after expires_in
+*/
+
+#[axum::debug_handler]
+pub async fn pushed_authorization_request(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    certificate: Option<Extension<ClientCertificate>>,
+    pf: Result<Form<PushedAuthorizationForm>, FormRejection>,
+) -> impl IntoResponse {
+    let mut pf = match pf {
+        Ok(Form(pf)) => pf,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_request", "detail": err.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    if pf.request_uri.is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_request", "detail": "request_uri must not be pushed" })),
+        )
+            .into_response();
+    }
+
+    let creds = match client_credentials(
+        &headers,
+        &uri,
+        std::mem::take(&mut pf.client_auth),
+        certificate.map(|Extension(c)| c),
+    ) {
+        Ok(creds) => creds,
+        Err(err) => return err.into_response(),
+    };
+
+    let client = match authenticate_client(
+        &app,
+        &TokenInput {
+            client_id: creds.client_id.clone(),
+            redirect_uri: None,
+        },
+        &creds.auth,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "invalid_client", "detail": e.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    let signed = match pf
+        .params
+        .apply_request_object(&app, &client.name, pf.request.as_deref())
+        .await
+    {
+        Ok(signed) => signed,
+        Err(detail) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_request_object", "detail": detail })),
+            )
+                .into_response();
+        }
+    };
+
+    let Some(request) = pf.params.into_request(client.name, signed) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_request", "detail": "redirect_uri is required" })),
+        )
+            .into_response();
+    };
+
+    match push_authorization_request(&app, &request).await {
+        Ok(request_uri) => (
+            StatusCode::CREATED,
+            Json(PushedAuthorizationResponse {
+                request_uri,
+                expires_in: PUSHED_REQUEST_EXPIRATION_SECS,
+            }),
+        )
+            .into_response(),
+        Err(e) if e.error == "server_error" => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.error, "detail": e.description })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": e.error, "detail": e.description })),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,100 @@
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::services::resource::{is_valid_resource_indicator, register_resource_service};
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct NewResourceRequest {
+    // what clients send as `resource` and tokens carry as `aud`, e.g. https://api.example.com
+    identifier: String,
+    name: String,
+    scopes: Vec<String>,
+}
+
+/*
+* POST /resources
+    { "identifier": "https://api.example.com", "name": "...", "scopes": ["orders:read", ...] }
+
+    Content-Type: application/json
+
+* OUTPUT
+* 201 { "status": "success", "id": 1, "identifier": "https://api.example.com", "scopes": [...] }
+
+* VALIDATE
+* identifier is an absolute URI without a fragment (RFC 8707 section 2)
+* at least one scope
+
+* CORE LOGIC
+* Register a protected resource (API): clients request tokens for it with `resource=<identifier>`
+* at /authorize and /token, those tokens get aud=<identifier> and only the scopes registered here
+*/
+
+#[axum::debug_handler]
+pub async fn register_resource(
+    State(app): State<AppState>,
+    new_resource: Result<Json<NewResourceRequest>, JsonRejection>,
+) -> impl IntoResponse {
+    let new_resource = match new_resource {
+        Ok(Json(resource)) => resource,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_json", "detail": err.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    if !is_valid_resource_indicator(&new_resource.identifier) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_resource", "detail": "identifier must be an absolute URI without a fragment" })),
+        )
+            .into_response();
+    }
+    if new_resource.scopes.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(
+                json!({ "error": "invalid_resource", "detail": "at least one scope is required" }),
+            ),
+        )
+            .into_response();
+    }
+
+    match register_resource_service(
+        &app,
+        &new_resource.identifier,
+        &new_resource.name,
+        &new_resource.scopes,
+    )
+    .await
+    {
+        Ok(id) => {
+            info!("Registered resource {} ({})", new_resource.identifier, id);
+            (
+                StatusCode::CREATED,
+                Json(json!({
+                    "status": "success",
+                    "id": id,
+                    "identifier": new_resource.identifier,
+                    "scopes": new_resource.scopes,
+                })),
+            )
+                .into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "registration_failed", "detail": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
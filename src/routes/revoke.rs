@@ -0,0 +1,112 @@
+use axum::{
+    extract::{rejection::FormRejection, Extension, Form, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::routes::client_auth::{client_credentials, ClientAuthForm};
+use crate::services::mtls::ClientCertificate;
+use crate::services::revoke::revoke as revoke_token;
+use crate::services::token::authenticate_client;
+use crate::services::TokenInput;
+use crate::state::AppState;
+
+#[derive(Deserialize)]
+pub struct RevokeForm {
+    token: String,
+    token_type_hint: Option<String>,
+    #[serde(flatten)]
+    client_auth: ClientAuthForm,
+}
+
+/*
+* POST /revoke
+    token=...
+    &token_type_hint=access_token|refresh_token
+    &client_id=...
+    &client_secret=...
+
+    Content-Type: application/x-www-form-urlencoded
+    client authentication as for POST /token
+
+* OUTPUT
+* 200 (empty body), also for unknown or already revoked tokens
+
+* VALIDATE
+* client authenticates with its registered method
+* token was issued to this client
+
+* CORE LOGIC
+* refresh token: revoke its whole family and deny-list the access tokens issued with it
+* access token: deny-list its jti in redis until it expires
+*/
+
+#[axum::debug_handler]
+pub async fn revoke(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+    certificate: Option<Extension<ClientCertificate>>,
+    form: Result<Form<RevokeForm>, FormRejection>,
+) -> impl IntoResponse {
+    let mut form = match form {
+        Ok(Form(form)) => form,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_request", "detail": err.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    let creds = match client_credentials(
+        &headers,
+        &uri,
+        std::mem::take(&mut form.client_auth),
+        certificate.map(|Extension(c)| c),
+    ) {
+        Ok(creds) => creds,
+        Err(err) => return err.into_response(),
+    };
+
+    let client = match authenticate_client(
+        &app,
+        &TokenInput {
+            client_id: creds.client_id.clone(),
+            redirect_uri: None,
+        },
+        &creds.auth,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "invalid_client", "detail": e.to_string() })),
+            )
+                .into_response();
+        }
+    };
+
+    match revoke_token(
+        &app,
+        client.id,
+        &creds.client_id,
+        &form.token,
+        form.token_type_hint.as_deref(),
+    )
+    .await
+    {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "server_error", "detail": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
@@ -1,155 +1,654 @@
 use axum::{
-    extract::{rejection::QueryRejection, Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{rejection::FormRejection, Extension, Form, State},
+    http::{HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::Deserialize;
 use serde_json::json;
 
+use crate::repositories::clients::Client;
+use crate::repositories::resources::Resource;
+use crate::routes::client_auth::{client_credentials, ClientAuthForm, ClientCredentials};
 use crate::services::authorize::AuthCodePayload;
 use crate::services::cache::redeem_code;
-use crate::services::issue_jwt;
+use crate::services::device::{poll_device_code, DevicePollError, DEVICE_CODE_GRANT_TYPE};
+use crate::services::dpop::{issue_dpop_nonce, verify_dpop_proof, DpopCheck};
+use crate::services::mtls::ClientCertificate;
+use crate::services::pkce::check_code_verifier;
+use crate::services::refresh_token::{
+    issue_refresh_token, link_access_token, rotate_refresh_token, RefreshOutcome,
+};
+use crate::services::resource::{find_resource, resource_scopes};
+use crate::services::token::{
+    authenticate_client, grant_scopes, AccessToken, AccessTokenGrant, Confirmation,
+};
+use crate::services::{issue_access_token, TokenInput};
 use crate::state::AppState;
 
 #[derive(Deserialize)]
-pub struct TokenQuery {
+pub struct TokenForm {
     grant_type: Option<String>,
     redirect_uri: Option<String>,
-    code: String,
-    client_id: String,
-    client_secret: String,
+    code: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    device_code: Option<String>,
+    scope: Option<String>,
+    resource: Option<String>,
+    #[serde(flatten)]
+    client_auth: ClientAuthForm,
 }
 
 #[derive(serde::Serialize)]
 pub struct TokenResponse {
     access_token: String,
-    refresh_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
     token_type: String,
     expires_in: u64,
+    scope: String,
+    // RFC 9396 section 7, what the token was granted for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authorization_details: Option<serde_json::Value>,
+}
+
+// Grants return the error response directly so handlers can use `?`
+type TokenResult = Result<TokenResponse, Response>;
+
+fn token_error(status: StatusCode, error: &str, detail: &str) -> Response {
+    (status, Json(json!({ "error": error, "detail": detail }))).into_response()
+}
+
+fn server_error(err: &anyhow::Error) -> Response {
+    token_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server_error",
+        &err.to_string(),
+    )
+}
+
+async fn authenticate(
+    app: &AppState,
+    creds: &ClientCredentials,
+    redirect_uri: Option<String>,
+) -> Result<Client, Response> {
+    authenticate_client(
+        app,
+        &TokenInput {
+            client_id: creds.client_id.clone(),
+            redirect_uri,
+        },
+        &creds.auth,
+    )
+    .await
+    .map_err(|e| token_error(StatusCode::UNAUTHORIZED, "invalid_client", &e.to_string()))
+}
+
+fn unauthorized_client(grant_type: &str) -> Response {
+    token_error(
+        StatusCode::BAD_REQUEST,
+        "unauthorized_client",
+        &format!("client is not registered for grant_type {grant_type}"),
+    )
+}
+
+// Only clients registered for the refresh_token grant get a refresh token
+async fn maybe_issue_refresh_token(
+    app: &AppState,
+    client: &Client,
+    grant: &AccessTokenGrant<'_>,
+    access_token: &AccessToken,
+    cnf: Option<&Confirmation>,
+) -> Result<Option<String>, Response> {
+    if !client.allows_grant("refresh_token") {
+        return Ok(None);
+    }
+
+    issue_refresh_token(app, client, grant, access_token, dpop_jkt(cnf))
+        .await
+        .map(Some)
+        .map_err(|e| server_error(&e))
+}
+
+fn invalid_target(detail: &str) -> Response {
+    token_error(StatusCode::BAD_REQUEST, "invalid_target", detail)
+}
+
+// RFC 8707: the `resource` parameter names the API the token is for
+async fn target_resource(
+    app: &AppState,
+    identifier: Option<&str>,
+) -> Result<Option<Resource>, Response> {
+    let Some(identifier) = identifier else {
+        return Ok(None);
+    };
+    match find_resource(app, identifier).await {
+        Ok(Some(resource)) => Ok(Some(resource)),
+        Ok(None) => Err(invalid_target("resource is not registered")),
+        Err(e) => Err(server_error(&e)),
+    }
+}
+
+// A token for a resource has it as audience and only its scopes, otherwise the client is the audience.
+// None if none of the scopes belong to the resource.
+fn token_audience(
+    client: &Client,
+    resource: Option<&Resource>,
+    scope: String,
+) -> Option<(String, String)> {
+    match resource {
+        Some(resource) => Some((
+            resource.identifier.clone(),
+            resource_scopes(&scope, resource)?,
+        )),
+        None => Some((client.name.clone(), scope)),
+    }
+}
+
+fn no_resource_scope() -> Response {
+    token_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_scope",
+        "none of the granted scopes are registered for the resource",
+    )
+}
+
+fn dpop_jkt(cnf: Option<&Confirmation>) -> Option<&str> {
+    cnf.and_then(|c| c.jkt.as_deref())
+}
+
+// RFC 9449: a DPoP header binds the issued tokens to the key that signed the proof
+async fn dpop_binding(
+    app: &AppState,
+    headers: &HeaderMap,
+    uri: &Uri,
+) -> Result<Option<String>, Response> {
+    let mut proofs = headers.get_all("dpop").iter();
+    let Some(proof) = proofs.next() else {
+        return Ok(None);
+    };
+    let (Ok(proof), None) = (proof.to_str(), proofs.next()) else {
+        return Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_dpop_proof",
+            "exactly one DPoP header is allowed",
+        ));
+    };
+
+    let htu = format!("{}{}", app.base_url(), uri.path());
+    match verify_dpop_proof(app, proof, "POST", &htu).await {
+        Ok(DpopCheck::Valid(jkt)) => Ok(Some(jkt)),
+        Ok(DpopCheck::UseNonce) => {
+            let nonce = issue_dpop_nonce(app).await.map_err(|e| server_error(&e))?;
+            let mut response = token_error(
+                StatusCode::BAD_REQUEST,
+                "use_dpop_nonce",
+                "DPoP proof must carry the nonce from the DPoP-Nonce header",
+            );
+            if let Ok(value) = HeaderValue::from_str(&nonce) {
+                response.headers_mut().insert("dpop-nonce", value);
+            }
+            Err(response)
+        }
+        Ok(DpopCheck::Invalid(reason)) => Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_dpop_proof",
+            reason,
+        )),
+        Err(e) => Err(server_error(&e)),
+    }
 }
 
 /*
-* POST /token?grant_type=...
-  &code=...
-  &redirect_uri=...
-  &client_id=...
-  &client_secret=...
+* POST /token
+    grant_type=...
+    &code=...
+    &redirect_uri=...
+    &code_verifier=...
+    &refresh_token=...
+    &device_code=...
+    &scope=...
+    &resource=... (optional, RFC 8707: a registered API, becomes the token's aud)
+    &client_id=...
+    &client_secret=...
 
     Content-Type: application/x-www-form-urlencoded
+    Authorization: Basic base64(client_id:client_secret) (instead of client_id/client_secret in the body)
+    or &client_assertion_type=urn:ietf:params:oauth:client-assertion-type:jwt-bearer&client_assertion=<signed JWT>
+      (instead of client_secret, for clients registered with private_key_jwt)
+    or only client_id, with the client certificate presented in the TLS handshake
+      (for clients registered with tls_client_auth / self_signed_tls_client_auth)
+    DPoP: <proof JWT> (optional, binds the tokens to the proof's key)
 
 * OUTPUT
-* 200 { "access_token": "{JWT}", "refresh_token": "{REFRESH_TOKEN}", "token_type": "Bearer", "expires_in": 3600 }
+* 200 { "access_token": "{JWT}", "refresh_token": "{REFRESH_TOKEN}", "token_type": "Bearer", "expires_in": 3600, "scope": "..." }
+*     token_type is "DPoP" when a DPoP proof was sent, expires_in is the client's access token lifetime
+* 400 { "error": "use_dpop_nonce", ... } with a DPoP-Nonce header, retry with that nonce in the proof
 
 * VALIDATE
+* client credentials come from exactly one of Basic auth or the body, never the query string
 * client_id and client_secret match
-* authorization code is valid, not expired, not reused
-* redirect_uri matches that of the authorization code
+* client has the grant_type registered (refresh tokens are only issued with the refresh_token grant)
+* authorization_code: code is valid, not expired, not reused
+* authorization_code: redirect_uri matches that of the authorization code
+* authorization_code: code_verifier matches the code_challenge sent to /authorize (PKCE)
+* authorization_code: scopes authorized at /authorize are still registered for the client
+* refresh_token: token was issued to this client, not expired (idle or absolute lifetime), revoked or already rotated
+* refresh_token: requested scope (optional) is within the scope originally granted
+* client_credentials: requested scopes are registered for the client
+* device_code: code was issued to this client and approved by the user, client polls no faster than interval
+* resource: registered, and for authorization_code the one authorized at /authorize (if any)
+* resource: at least one of the granted scopes is registered for it
+* DPoP: only looked at once the client is authenticated
+* DPoP: proof signed by its jwk, typ dpop+jwt, htm/htu match this request, fresh iat, server nonce, jti not replayed
+* DPoP: a refresh token bound to a DPoP key is only accepted with a proof for that key
 
 * CORE LOGIC
-* Issue access token (JWT)
-* Issue refresh token (long-lived random string, stored in db)
+* Issue access token (JWT, or an opaque handle kept in redis for clients registered with access_token_format=opaque),
+* for the resource (aud, scopes limited to the resource's) or else the client (aud = client_id),
+* bound via cnf to the mTLS certificate (x5t#S256) and/or the DPoP key (jkt)
+* Issue refresh token (long-lived random string, stored hashed in db)
 * Invalidate authorization code (one-time use)
+* Rotate refresh token (one-time use, reuse revokes the whole token family)
 */
 
 #[axum::debug_handler]
 pub async fn token(
     State(app): State<AppState>,
-    tq: Result<Query<TokenQuery>, QueryRejection>,
+    headers: HeaderMap,
+    uri: Uri,
+    certificate: Option<Extension<ClientCertificate>>,
+    tf: Result<Form<TokenForm>, FormRejection>,
 ) -> impl IntoResponse {
-    let tq = match tq {
-        Ok(Query(tq)) => tq,
+    let mut tf = match tf {
+        Ok(Form(tf)) => tf,
         Err(err) => {
             return (
                 StatusCode::BAD_REQUEST,
-                Json(json!({ "error": "invalid_query", "detail": err.to_string() })),
+                Json(json!({ "error": "invalid_request", "detail": err.to_string() })),
             )
                 .into_response();
         }
     };
 
-    //TODO: other grant_types
-    let grant_type = tq.grant_type.as_deref().unwrap_or("authorization_code");
-    if grant_type != "authorization_code" {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "unsupported_grant_type", "detail": "only authorization_code is supported" })),
-        )
-        .into_response();
-    }
+    let creds = match client_credentials(
+        &headers,
+        &uri,
+        std::mem::take(&mut tf.client_auth),
+        certificate.map(|Extension(c)| c),
+    ) {
+        Ok(creds) => creds,
+        Err(err) => return err.into_response(),
+    };
 
-    let serialized_payload_option = match redeem_code(&app, tq.code.as_str()).await {
-        Ok(v) => v,
-        Err(e) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "cache_error", "detail": e.to_string() })),
-            )
-                .into_response();
-        }
+    let grant_type = tf
+        .grant_type
+        .clone()
+        .unwrap_or_else(|| "authorization_code".to_string());
+    // for authorization_code the client must also have the redirect_uri registered
+    let redirect_uri =
+        (grant_type == "authorization_code").then(|| tf.redirect_uri.clone().unwrap_or_default());
+    let client = match authenticate(&app, &creds, redirect_uri).await {
+        Ok(client) => client,
+        Err(err) => return err,
+    };
+
+    // only after authentication, a proof costs a nonce and a jti in redis
+    let dpop_jkt = match dpop_binding(&app, &headers, &uri).await {
+        Ok(jkt) => jkt,
+        Err(err) => return err,
     };
+    let cnf = Confirmation::new(creds.auth.certificate_thumbprint(), dpop_jkt);
 
-    let Some(serialized_payload) = serialized_payload_option else {
-        return (
+    let result = match grant_type.as_str() {
+        "authorization_code" => authorization_code_grant(&app, tf, client, cnf).await,
+        "refresh_token" => refresh_token_grant(&app, tf, client, cnf).await,
+        "client_credentials" => client_credentials_grant(&app, tf, client, cnf).await,
+        gt if gt == DEVICE_CODE_GRANT_TYPE => device_code_grant(&app, tf, client, cnf).await,
+        _ => Err(token_error(
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "invalid_grant", "detail": "authorization code not found or already used" })),
+            "unsupported_grant_type",
+            "only authorization_code, refresh_token, client_credentials and device_code are supported",
+        )),
+    };
+
+    match result {
+        Ok(response) => Json(json!(response)).into_response(),
+        Err(err) => err,
+    }
+}
+
+// One-time use: the code is gone from redis once read
+async fn redeem_auth_code(app: &AppState, code: &str) -> Result<AuthCodePayload, Response> {
+    let Some(serialized_payload) = redeem_code(app, code).await.map_err(|e| {
+        token_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "cache_error",
+            &e.to_string(),
         )
-            .into_response();
+    })?
+    else {
+        return Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            "authorization code not found or already used",
+        ));
     };
 
-    let d_payload: AuthCodePayload = match serde_json::from_str(&serialized_payload) {
-        Ok(sp) => sp,
-        Err(e) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "invalid_grant", "detail": format!("failed to parse auth code payload: {}", e.to_string()) })),
-            )
-                .into_response();
-        }
+    serde_json::from_str(&serialized_payload).map_err(|e| {
+        token_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "invalid_grant",
+            &format!("failed to parse auth code payload: {e}"),
+        )
+    })
+}
+
+async fn authorization_code_grant(
+    app: &AppState,
+    tf: TokenForm,
+    client: Client,
+    cnf: Option<Confirmation>,
+) -> TokenResult {
+    let Some(code) = tf.code.as_deref() else {
+        return Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "code is required",
+        ));
     };
 
-    if d_payload.redirect_uri != tq.redirect_uri.as_deref().unwrap_or_default() {
-        return (
+    if !client.allows_grant("authorization_code") {
+        return Err(unauthorized_client("authorization_code"));
+    }
+
+    let d_payload = redeem_auth_code(app, code).await?;
+
+    if d_payload.redirect_uri != tf.redirect_uri.as_deref().unwrap_or_default() {
+        return Err(token_error(
             StatusCode::UNAUTHORIZED,
-            Json(json!({ "error": "invalid_grant", "detail": "redirect_uri does not match" })),
-        )
-            .into_response();
+            "invalid_grant",
+            "redirect_uri does not match",
+        ));
     }
 
-    if d_payload.client_id != tq.client_id {
-        return (
+    if d_payload.client_id != client.name {
+        return Err(token_error(
             StatusCode::UNAUTHORIZED,
-            Json(json!({ "error": "invalid_grant", "detail": "client_id does not match" })),
-        )
-            .into_response();
+            "invalid_grant",
+            "client_id does not match",
+        ));
+    }
+
+    check_code_verifier(
+        d_payload.code_challenge.as_deref(),
+        d_payload.code_challenge_method.as_deref(),
+        tf.code_verifier.as_deref(),
+    )
+    .map_err(|reason| token_error(StatusCode::BAD_REQUEST, "invalid_grant", reason))?;
+
+    // the registered scopes may have changed since the user consented
+    let allowed = client.scopes.as_deref().unwrap_or_default();
+    if !d_payload.scopes.iter().all(|s| allowed.contains(s)) {
+        return Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_scope",
+            "authorized scope is no longer registered for this client",
+        ));
+    }
+    if tf.resource.is_some() && d_payload.resource.is_some() && tf.resource != d_payload.resource {
+        return Err(invalid_target("resource was not authorized at /authorize"));
+    }
+    let resource = target_resource(app, tf.resource.or(d_payload.resource).as_deref()).await?;
+    let (audience, scope) = token_audience(&client, resource.as_ref(), d_payload.scopes.join(" "))
+        .ok_or_else(no_resource_scope)?;
+
+    let grant = AccessTokenGrant {
+        user_id: &d_payload.user_id,
+        audience: &audience,
+        scope: &scope,
+        authorization_details: d_payload.authorization_details.as_ref(),
+    };
+    let access_token = issue_access_token(app, &client, &grant, cnf.clone())
+        .await
+        .map_err(|e| {
+            token_error(
+                StatusCode::UNAUTHORIZED,
+                "token_issuance_failed",
+                &e.to_string(),
+            )
+        })?;
+
+    let refresh_token =
+        maybe_issue_refresh_token(app, &client, &grant, &access_token, cnf.as_ref()).await?;
+
+    Ok(TokenResponse {
+        access_token: access_token.token,
+        refresh_token,
+        token_type: Confirmation::token_type(cnf.as_ref()).to_string(),
+        expires_in: access_token.expires_in,
+        scope,
+        authorization_details: d_payload.authorization_details,
+    })
+}
+
+async fn refresh_token_grant(
+    app: &AppState,
+    tf: TokenForm,
+    client: Client,
+    cnf: Option<Confirmation>,
+) -> TokenResult {
+    let Some(presented) = tf.refresh_token.as_deref() else {
+        return Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "refresh_token is required",
+        ));
+    };
+
+    if !client.allows_grant("refresh_token") {
+        return Err(unauthorized_client("refresh_token"));
     }
+    // checked before rotating, a failed request must not cost the client its refresh token
+    let resource = target_resource(app, tf.resource.as_deref()).await?;
 
-    let access_token = match issue_jwt(
-        &app,
-        d_payload.user_id.as_str(),
-        &tq.client_id,
-        "openid", // TODO: derive scopes from auth code
-        tq.client_secret.as_bytes(),
-        tq.redirect_uri.unwrap_or_default().as_str(),
+    let rotated = match rotate_refresh_token(
+        app,
+        &client,
+        presented,
+        tf.scope.as_deref(),
+        resource.as_ref(),
+        dpop_jkt(cnf.as_ref()),
     )
     .await
     {
-        Ok(access_token) => access_token,
-        Err(e) => {
-            return (
+        Ok(RefreshOutcome::Rotated(rotated)) => rotated,
+        Ok(RefreshOutcome::InvalidScope) => {
+            return Err(token_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_scope",
+                "requested scope exceeds the scope originally granted, or none of it belongs to the resource",
+            ));
+        }
+        Ok(RefreshOutcome::Rejected(reason)) => {
+            return Err(token_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+                reason,
+            ));
+        }
+        Err(e) => return Err(server_error(&e)),
+    };
+
+    let audience = resource.map_or_else(|| client.name.clone(), |r| r.identifier);
+    let grant = AccessTokenGrant {
+        user_id: &rotated.user_id,
+        audience: &audience,
+        scope: &rotated.scope,
+        authorization_details: rotated.authorization_details.as_ref(),
+    };
+    let access_token = issue_access_token(app, &client, &grant, cnf.clone())
+        .await
+        .map_err(|e| {
+            token_error(
                 StatusCode::UNAUTHORIZED,
-                Json(json!({ "error": "token_issuance_failed", "detail": e.to_string() })),
+                "token_issuance_failed",
+                &e.to_string(),
             )
-                .into_response();
+        })?;
+
+    link_access_token(app, rotated.id, &access_token)
+        .await
+        .map_err(|e| server_error(&e))?;
+
+    Ok(TokenResponse {
+        access_token: access_token.token,
+        refresh_token: Some(rotated.refresh_token),
+        token_type: Confirmation::token_type(cnf.as_ref()).to_string(),
+        expires_in: access_token.expires_in,
+        scope: rotated.scope,
+        authorization_details: rotated.authorization_details,
+    })
+}
+
+// No user is involved: the client acts on its own behalf, so it is also the subject
+async fn client_credentials_grant(
+    app: &AppState,
+    tf: TokenForm,
+    client: Client,
+    cnf: Option<Confirmation>,
+) -> TokenResult {
+    if !client.allows_grant("client_credentials") {
+        return Err(unauthorized_client("client_credentials"));
+    }
+
+    let Some(scope) = grant_scopes(
+        tf.scope.as_deref(),
+        client.scopes.as_deref().unwrap_or_default(),
+    ) else {
+        return Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_scope",
+            "requested scope is not registered for this client",
+        ));
+    };
+    let resource = target_resource(app, tf.resource.as_deref()).await?;
+    let (audience, scope) =
+        token_audience(&client, resource.as_ref(), scope).ok_or_else(no_resource_scope)?;
+
+    let grant = AccessTokenGrant {
+        user_id: &client.name,
+        audience: &audience,
+        scope: &scope,
+        authorization_details: None,
+    };
+    let access_token = issue_access_token(app, &client, &grant, cnf.clone())
+        .await
+        .map_err(|e| {
+            token_error(
+                StatusCode::UNAUTHORIZED,
+                "token_issuance_failed",
+                &e.to_string(),
+            )
+        })?;
+
+    Ok(TokenResponse {
+        access_token: access_token.token,
+        refresh_token: None,
+        token_type: Confirmation::token_type(cnf.as_ref()).to_string(),
+        expires_in: access_token.expires_in,
+        scope,
+        authorization_details: None,
+    })
+}
+
+fn device_poll_error(poll: &DevicePollError) -> Response {
+    let (error, detail) = match poll {
+        DevicePollError::Pending => (
+            "authorization_pending",
+            "the user has not approved the device yet",
+        ),
+        DevicePollError::SlowDown => (
+            "slow_down",
+            "polling too fast, increase the interval by 5 seconds",
+        ),
+        DevicePollError::Denied => ("access_denied", "the user denied the request"),
+        DevicePollError::Unknown => (
+            "invalid_grant",
+            "device_code is unknown, expired or already used",
+        ),
+        DevicePollError::ClientMismatch => {
+            ("invalid_grant", "device_code was not issued to this client")
         }
     };
+    token_error(StatusCode::BAD_REQUEST, error, detail)
+}
 
-    let response: TokenResponse = TokenResponse {
-        access_token,
-        refresh_token: "some_refresh_token".to_string(),
-        token_type: "Bearer".to_string(),
-        expires_in: 3600,
+async fn device_code_grant(
+    app: &AppState,
+    tf: TokenForm,
+    client: Client,
+    cnf: Option<Confirmation>,
+) -> TokenResult {
+    let Some(device_code) = tf.device_code.as_deref() else {
+        return Err(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "device_code is required",
+        ));
     };
 
-    Json(json!(response)).into_response()
+    if !client.allows_grant(DEVICE_CODE_GRANT_TYPE) {
+        return Err(unauthorized_client(DEVICE_CODE_GRANT_TYPE));
+    }
+
+    let payload = match poll_device_code(app, &client.name, device_code).await {
+        Ok(Ok(payload)) => payload,
+        Ok(Err(poll)) => return Err(device_poll_error(&poll)),
+        Err(e) => {
+            return Err(token_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "cache_error",
+                &e.to_string(),
+            ));
+        }
+    };
+
+    let Some(user_id) = payload.user_id.as_deref() else {
+        return Err(server_error(&anyhow::anyhow!(
+            "approved device code has no user"
+        )));
+    };
+
+    let resource = target_resource(app, tf.resource.as_deref()).await?;
+    let (audience, scope) = token_audience(&client, resource.as_ref(), payload.scopes.join(" "))
+        .ok_or_else(no_resource_scope)?;
+    let grant = AccessTokenGrant {
+        user_id,
+        audience: &audience,
+        scope: &scope,
+        authorization_details: None,
+    };
+    let access_token = issue_access_token(app, &client, &grant, cnf.clone())
+        .await
+        .map_err(|e| {
+            token_error(
+                StatusCode::UNAUTHORIZED,
+                "token_issuance_failed",
+                &e.to_string(),
+            )
+        })?;
+
+    let refresh_token =
+        maybe_issue_refresh_token(app, &client, &grant, &access_token, cnf.as_ref()).await?;
+
+    Ok(TokenResponse {
+        access_token: access_token.token,
+        refresh_token,
+        token_type: Confirmation::token_type(cnf.as_ref()).to_string(),
+        expires_in: access_token.expires_in,
+        scope,
+        authorization_details: None,
+    })
 }
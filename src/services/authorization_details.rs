@@ -0,0 +1,138 @@
+use serde_json::{Map, Value};
+
+use crate::repositories::clients::ClientSettings;
+
+/*
+ * Clients register a JSON Schema per authorization details type they use, e.g.
+ * { "payment_initiation": { "type": "object", "required": ["instructedAmount"], ... } }
+ * Anything outside the registered types is rejected (RFC 9396 section 5).
+ */
+pub fn check_authorization_details_types(types: &Value) -> Result<(), String> {
+    let Some(types) = types.as_object() else {
+        return Err("authorization_details_types must map each type to a JSON Schema".to_string());
+    };
+    for (name, schema) in types {
+        if let Err(e) = jsonschema::validator_for(schema) {
+            return Err(format!(
+                "schema for authorization details type {name} is invalid: {e}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn registered_types(settings: &ClientSettings) -> anyhow::Result<Map<String, Value>> {
+    let Some(types) = settings.authorization_details_types.as_deref() else {
+        return Ok(Map::new());
+    };
+    match serde_json::from_str(types)? {
+        Value::Object(types) => Ok(types),
+        _ => Err(anyhow::anyhow!(
+            "Registered authorization details types are not an object"
+        )),
+    }
+}
+
+// RFC 9396 section 2: a JSON array of objects, each with a `type` the client registered
+pub fn validate_authorization_details(
+    settings: &ClientSettings,
+    authorization_details: &str,
+) -> anyhow::Result<Value> {
+    let details: Value = serde_json::from_str(authorization_details)
+        .map_err(|e| anyhow::anyhow!("authorization_details is not valid JSON: {e}"))?;
+    let Some(entries) = details.as_array().filter(|entries| !entries.is_empty()) else {
+        return Err(anyhow::anyhow!(
+            "authorization_details must be a non-empty JSON array"
+        ));
+    };
+
+    let types = registered_types(settings)?;
+    for entry in entries {
+        let Some(kind) = entry.get("type").and_then(Value::as_str) else {
+            return Err(anyhow::anyhow!(
+                "every authorization_details entry needs a type"
+            ));
+        };
+        let Some(schema) = types.get(kind) else {
+            return Err(anyhow::anyhow!(
+                "authorization details type {kind} is not registered for this client"
+            ));
+        };
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| anyhow::anyhow!("Registered schema for {kind} is invalid: {e}"))?;
+        if let Err(e) = validator.validate(entry) {
+            return Err(anyhow::anyhow!(
+                "authorization_details entry of type {kind} is invalid: {e}"
+            ));
+        }
+    }
+    Ok(details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn settings() -> ClientSettings {
+        ClientSettings {
+            authorization_details_types: Some(
+                json!({
+                    "payment_initiation": {
+                        "type": "object",
+                        "required": ["instructedAmount"],
+                        "properties": { "instructedAmount": { "type": "number" } }
+                    }
+                })
+                .to_string(),
+            ),
+            ..ClientSettings::default()
+        }
+    }
+
+    #[test]
+    fn registered_types_need_valid_schemas() {
+        assert!(check_authorization_details_types(&json!({ "a": { "type": "object" } })).is_ok());
+        assert!(check_authorization_details_types(&json!(["a"])).is_err());
+        assert!(check_authorization_details_types(&json!({ "a": { "type": 12 } })).is_err());
+    }
+
+    #[test]
+    fn entries_matching_their_schema_are_accepted() {
+        let details = r#"[{ "type": "payment_initiation", "instructedAmount": 12.5 }]"#;
+        assert!(validate_authorization_details(&settings(), details).is_ok());
+    }
+
+    #[test]
+    fn entries_breaking_their_schema_are_rejected() {
+        for details in [
+            r#"[{ "type": "payment_initiation" }]"#,
+            r#"[{ "type": "payment_initiation", "instructedAmount": "12.5" }]"#,
+        ] {
+            assert!(
+                validate_authorization_details(&settings(), details).is_err(),
+                "{details} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn only_registered_types_are_accepted() {
+        let details = r#"[{ "type": "account_information" }]"#;
+        assert!(validate_authorization_details(&settings(), details).is_err());
+        let untyped = r#"[{ "instructedAmount": 12.5 }]"#;
+        assert!(validate_authorization_details(&settings(), untyped).is_err());
+        let no_types = r#"[{ "type": "payment_initiation", "instructedAmount": 12.5 }]"#;
+        assert!(validate_authorization_details(&ClientSettings::default(), no_types).is_err());
+    }
+
+    #[test]
+    fn details_must_be_a_non_empty_array() {
+        for details in ["[]", "{}", "not json"] {
+            assert!(
+                validate_authorization_details(&settings(), details).is_err(),
+                "{details} was accepted"
+            );
+        }
+    }
+}
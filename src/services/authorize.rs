@@ -1,27 +1,133 @@
-use crate::{repositories::clients::get_by_client_id, state::AppState};
+use crate::{
+    repositories::clients::{get_by_client_id, Client, ResponseMode},
+    state::AppState,
+};
 
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 
-use crate::services::cache::store_auth_code;
+use crate::services::authorization_details::validate_authorization_details;
+use crate::services::cache::{
+    store_auth_code, store_consent, store_pushed_request, take_consent, take_pushed_request,
+};
+use crate::services::lifetimes::Lifetimes;
+use crate::services::pkce::validate_challenge;
+use crate::services::resource::find_resource;
 
-#[derive(Debug)]
-pub struct AuthorizeInput {
+// What the client asks for, sent to /authorize directly or pushed to /par first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthorizationRequest {
     pub client_id: String,
     pub redirect_uri: String,
+    pub response_type: Option<String>,
+    pub response_mode: Option<String>,
     pub scopes: Vec<String>,
     pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    // RFC 8707 resource indicator, the API the tokens are meant for
+    pub resource: Option<String>,
+    // RFC 9396, raw JSON as sent by the client
+    pub authorization_details: Option<String>,
+    // RFC 9101, the parameters came from a verified request object
+    #[serde(default)]
+    pub signed: bool,
+}
+
+#[derive(Debug)]
+pub struct AuthorizeInput {
+    pub request: AuthorizationRequest,
+    // the request came from /par through its request_uri
+    pub pushed: bool,
     pub user_id: String,
+    // when the user logged in, unix seconds
+    pub authenticated_at: i64,
 }
 
 pub struct AuthorizeResult {
+    // the JARM response is signed for and possibly encrypted to this client
+    pub client: Box<Client>,
     pub code: String,
     pub redirect_uri: String,
-    pub state: String,
+    pub state: Option<String>,
+    pub response_mode: ResponseMode,
+}
+
+pub enum AuthorizeOutcome {
+    Code(AuthorizeResult),
+    // the user has to see and approve the authorization_details first, see `complete_consent`
+    Consent(PendingConsent),
+}
+
+pub struct PendingConsent {
+    pub consent_id: String,
+    pub client_id: String,
+    pub authorization_details: serde_json::Value,
+}
+
+pub enum ConsentOutcome {
+    Approved(AuthorizeResult),
+    Denied(ErrorRedirect),
+}
+
+// RFC 6749 section 4.1.2.1 error code and description
+#[derive(Debug)]
+pub struct AuthorizeError {
+    pub error: &'static str,
+    pub description: String,
+}
+
+impl AuthorizeError {
+    fn new(error: &'static str, description: impl Into<String>) -> Self {
+        AuthorizeError {
+            error,
+            description: description.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for AuthorizeError {
+    fn from(err: anyhow::Error) -> Self {
+        AuthorizeError::new("server_error", err.to_string())
+    }
+}
+
+// An error for the client, sent to its redirect_uri with the request's state
+pub struct ErrorRedirect {
+    pub client: Box<Client>,
+    pub redirect_uri: String,
+    pub state: Option<String>,
+    pub response_mode: ResponseMode,
+    pub error: AuthorizeError,
 }
 
+pub enum AuthorizeFailure {
+    // unknown client or a redirect_uri it did not register: nothing may be sent to that redirect_uri
+    InvalidClient(&'static str),
+    // the user logged in longer ago than the client accepts
+    LoginRequired,
+    Redirect(ErrorRedirect),
+    Server(anyhow::Error),
+}
+
+// Everything needed to issue the code once the user approved
+#[derive(Serialize, Deserialize)]
+struct ConsentPayload {
+    auth_code: AuthCodePayload,
+    auth_code_ttl_secs: u64,
+    #[serde(default)]
+    response_mode: ResponseMode,
+}
+
+static CONSENT_EXPIRATION_SECS: u64 = 10 * 60; // 10 minutes
+
+// RFC 9126 section 2.2
+pub static REQUEST_URI_PREFIX: &str = "urn:ietf:params:oauth:request_uri:";
+pub static PUSHED_REQUEST_EXPIRATION_SECS: u64 = 60; // 1 minute
+
 #[derive(Serialize, Deserialize)]
 pub struct AuthCodePayload {
     pub client_id: String,
@@ -29,6 +135,14 @@ pub struct AuthCodePayload {
     pub redirect_uri: String,
     pub scopes: Vec<String>,
     pub state: Option<String>,
+    #[serde(default)]
+    pub code_challenge: Option<String>,
+    #[serde(default)]
+    pub code_challenge_method: Option<String>,
+    #[serde(default)]
+    pub resource: Option<String>,
+    #[serde(default)]
+    pub authorization_details: Option<serde_json::Value>,
 }
 
 fn generate_auth_code() -> String {
@@ -37,38 +151,334 @@ fn generate_auth_code() -> String {
     URL_SAFE_NO_PAD.encode(bytes)
 }
 
+async fn issue_code(
+    app: &AppState,
+    client: &Client,
+    payload: &AuthCodePayload,
+    ttl_secs: u64,
+    response_mode: ResponseMode,
+) -> anyhow::Result<AuthorizeResult> {
+    let code = generate_auth_code();
+    store_auth_code(
+        app,
+        &code,
+        serde_json::to_string(payload)?.as_str(),
+        ttl_secs,
+    )
+    .await?;
+
+    Ok(AuthorizeResult {
+        client: Box::new(client.clone()),
+        code,
+        redirect_uri: payload.redirect_uri.clone(),
+        state: payload.state.clone(),
+        response_mode,
+    })
+}
+
+// query unless the client asks for one of the modes it registered
+fn response_mode(
+    client: &Client,
+    request: &AuthorizationRequest,
+) -> Result<ResponseMode, AuthorizeError> {
+    let Some(requested) = request.response_mode.as_deref() else {
+        return Ok(ResponseMode::default());
+    };
+    match ResponseMode::parse(requested) {
+        Some(mode) if client.settings.response_modes.contains(&mode) => Ok(mode),
+        Some(_) => Err(AuthorizeError::new(
+            "invalid_request",
+            format!("response_mode {requested} is not registered for this client"),
+        )),
+        None => Err(AuthorizeError::new(
+            "invalid_request",
+            format!("Unsupported response_mode {requested}"),
+        )),
+    }
+}
+
+// What /authorize and /par learn from checking a request
+struct CheckedRequest {
+    code_challenge_method: Option<String>,
+    authorization_details: Option<serde_json::Value>,
+}
+
+// The checks that need no user, for a client whose redirect_uri is already verified
+async fn check_request(
+    app: &AppState,
+    client: &Client,
+    request: &AuthorizationRequest,
+) -> Result<CheckedRequest, AuthorizeError> {
+    match request.response_type.as_deref() {
+        Some("code") => {}
+        Some(_) => {
+            return Err(AuthorizeError::new(
+                "unsupported_response_type",
+                "only response_type=code is supported",
+            ));
+        }
+        None => {
+            return Err(AuthorizeError::new(
+                "invalid_request",
+                "response_type is required",
+            ));
+        }
+    }
+
+    response_mode(client, request)?;
+
+    if client.settings.require_signed_request_object && !request.signed {
+        return Err(AuthorizeError::new(
+            "invalid_request",
+            "this client must send its authorization requests as a signed request object",
+        ));
+    }
+
+    let allowed = client.scopes.as_deref().unwrap_or_default();
+    if let Some(scope) = request.scopes.iter().find(|s| !allowed.contains(s)) {
+        return Err(AuthorizeError::new(
+            "invalid_scope",
+            format!("Scope {scope} is not registered for this client"),
+        ));
+    }
+
+    let code_challenge_method = match request.code_challenge.as_deref() {
+        Some(challenge) => Some(
+            validate_challenge(challenge, request.code_challenge_method.as_deref())
+                .map_err(|e| AuthorizeError::new("invalid_request", e.to_string()))?,
+        ),
+        None if client.settings.require_pkce => {
+            return Err(AuthorizeError::new(
+                "invalid_request",
+                "code_challenge is required for this client",
+            ));
+        }
+        None => None,
+    };
+
+    // the user consents to scopes of that resource only
+    if let Some(identifier) = request.resource.as_deref() {
+        let Some(registered) = find_resource(app, identifier).await? else {
+            return Err(AuthorizeError::new(
+                "invalid_target",
+                format!("Unknown resource {identifier}"),
+            ));
+        };
+        if let Some(scope) = request
+            .scopes
+            .iter()
+            .find(|s| !registered.scopes.contains(s))
+        {
+            return Err(AuthorizeError::new(
+                "invalid_scope",
+                format!("Scope {scope} is not registered for resource {identifier}"),
+            ));
+        }
+    }
+
+    // RFC 9396 section 5
+    let authorization_details = request
+        .authorization_details
+        .as_deref()
+        .map(|details| validate_authorization_details(&client.settings, details))
+        .transpose()
+        .map_err(|e| AuthorizeError::new("invalid_authorization_details", e.to_string()))?;
+
+    Ok(CheckedRequest {
+        code_challenge_method,
+        authorization_details,
+    })
+}
+
+// RFC 9126: validate the request up front and hand back the request_uri that stands in for it
+pub async fn push_authorization_request(
+    app: &AppState,
+    request: &AuthorizationRequest,
+) -> Result<String, AuthorizeError> {
+    let client = get_by_client_id(app.pool(), &request.client_id)
+        .await
+        .map_err(anyhow::Error::from)?;
+    let Some(client) = client.filter(|c| c.has_redirect_uri(&request.redirect_uri)) else {
+        return Err(AuthorizeError::new(
+            "invalid_request",
+            "redirect_uri is not registered for this client",
+        ));
+    };
+    check_request(app, &client, request).await?;
+
+    let handle = generate_auth_code();
+    let serialized = serde_json::to_string(request).map_err(anyhow::Error::from)?;
+    store_pushed_request(app, &handle, &serialized, PUSHED_REQUEST_EXPIRATION_SECS).await?;
+    Ok(format!("{REQUEST_URI_PREFIX}{handle}"))
+}
+
+// One-time use. None if the request_uri is unknown, expired or was pushed by another client.
+pub async fn redeem_request_uri(
+    app: &AppState,
+    client_id: &str,
+    request_uri: &str,
+) -> anyhow::Result<Option<AuthorizationRequest>> {
+    let Some(handle) = request_uri.strip_prefix(REQUEST_URI_PREFIX) else {
+        return Ok(None);
+    };
+    let Some(serialized) = take_pushed_request(app, handle).await? else {
+        return Ok(None);
+    };
+    let request: AuthorizationRequest = serde_json::from_str(&serialized)?;
+    Ok((request.client_id == client_id).then_some(request))
+}
+
+// Until the redirect_uri is known to be the client's, errors stay on Loom's side
+async fn trusted_client(
+    app: &AppState,
+    request: &AuthorizationRequest,
+) -> Result<Client, AuthorizeFailure> {
+    let client = match get_by_client_id(app.pool(), &request.client_id).await {
+        Ok(Some(client)) => client,
+        Ok(None) => return Err(AuthorizeFailure::InvalidClient("unknown client_id")),
+        Err(err) => return Err(AuthorizeFailure::Server(err.into())),
+    };
+    if !client.has_redirect_uri(&request.redirect_uri) {
+        return Err(AuthorizeFailure::InvalidClient(
+            "redirect_uri is not registered for this client",
+        ));
+    }
+    Ok(client)
+}
+
 pub async fn authorize(
     app: &AppState,
     authorize_input: AuthorizeInput,
-) -> Result<AuthorizeResult, anyhow::Error> {
-    // client returned here
-    let Some(_) = get_by_client_id(app.pool(), &authorize_input).await? else {
-        return Err(anyhow::anyhow!("Client not found"));
+) -> Result<AuthorizeOutcome, AuthorizeFailure> {
+    let client = trusted_client(app, &authorize_input.request).await?;
+
+    let lifetimes = app.lifetimes().for_client(&client.settings.lifetimes);
+    let session_age = OffsetDateTime::now_utc().unix_timestamp() - authorize_input.authenticated_at;
+    if session_age.unsigned_abs() > lifetimes.session {
+        return Err(AuthorizeFailure::LoginRequired);
+    }
+
+    let redirect_uri = authorize_input.request.redirect_uri.clone();
+    let state = authorize_input.request.state.clone();
+    // a response_mode the client may not use is reported the default way
+    let (response_mode, result) = match response_mode(&client, &authorize_input.request) {
+        Ok(mode) => (
+            mode,
+            grant(app, &client, authorize_input, &lifetimes, mode).await,
+        ),
+        Err(error) => (ResponseMode::default(), Err(error)),
     };
+    result.map_err(|error| {
+        AuthorizeFailure::Redirect(ErrorRedirect {
+            client: Box::new(client),
+            redirect_uri,
+            state,
+            response_mode,
+            error,
+        })
+    })
+}
+
+// A code right away, or a consent to ask for first
+async fn grant(
+    app: &AppState,
+    client: &Client,
+    authorize_input: AuthorizeInput,
+    lifetimes: &Lifetimes,
+    response_mode: ResponseMode,
+) -> Result<AuthorizeOutcome, AuthorizeError> {
+    if client.settings.require_pushed_authorization_requests && !authorize_input.pushed {
+        return Err(AuthorizeError::new(
+            "invalid_request",
+            "this client must push its authorization requests to /par",
+        ));
+    }
 
-    let AuthorizeInput {
+    let CheckedRequest {
+        code_challenge_method,
+        authorization_details,
+    } = check_request(app, client, &authorize_input.request).await?;
+
+    let AuthorizationRequest {
         client_id,
-        user_id,
         redirect_uri,
         scopes,
         state,
-    } = authorize_input;
-
-    let code = generate_auth_code();
+        code_challenge,
+        resource,
+        ..
+    } = authorize_input.request;
 
     let payload = AuthCodePayload {
         client_id,
-        user_id,
-        redirect_uri: redirect_uri.clone(),
+        user_id: authorize_input.user_id,
+        redirect_uri,
         scopes,
-        state: state.clone(),
+        state,
+        code_challenge,
+        code_challenge_method,
+        resource,
+        authorization_details,
     };
 
-    store_auth_code(app, &code, serde_json::to_string(&payload)?.as_str()).await?;
+    let Some(authorization_details) = payload.authorization_details.clone() else {
+        return Ok(AuthorizeOutcome::Code(
+            issue_code(app, client, &payload, lifetimes.auth_code, response_mode).await?,
+        ));
+    };
 
-    Ok(AuthorizeResult {
-        code,
-        redirect_uri,
-        state: state.unwrap_or_default(),
-    })
+    let consent_id = generate_auth_code();
+    let client_id = payload.client_id.clone();
+    let pending = ConsentPayload {
+        auth_code: payload,
+        auth_code_ttl_secs: lifetimes.auth_code,
+        response_mode,
+    };
+    let serialized = serde_json::to_string(&pending).map_err(anyhow::Error::from)?;
+    store_consent(app, &consent_id, &serialized, CONSENT_EXPIRATION_SECS).await?;
+
+    Ok(AuthorizeOutcome::Consent(PendingConsent {
+        consent_id,
+        client_id,
+        authorization_details,
+    }))
+}
+
+// The user's answer on the consent page. None if the consent is unknown, expired or not theirs.
+pub async fn complete_consent(
+    app: &AppState,
+    consent_id: &str,
+    user_id: &str,
+    approved: bool,
+) -> anyhow::Result<Option<ConsentOutcome>> {
+    let Some(serialized) = take_consent(app, consent_id).await? else {
+        return Ok(None);
+    };
+    let pending: ConsentPayload = serde_json::from_str(&serialized)?;
+    if pending.auth_code.user_id != user_id {
+        return Ok(None);
+    }
+
+    let Some(client) = get_by_client_id(app.pool(), &pending.auth_code.client_id).await? else {
+        return Err(anyhow::anyhow!("Client not found"));
+    };
+
+    if !approved {
+        return Ok(Some(ConsentOutcome::Denied(ErrorRedirect {
+            client: Box::new(client),
+            redirect_uri: pending.auth_code.redirect_uri,
+            state: pending.auth_code.state,
+            response_mode: pending.response_mode,
+            error: AuthorizeError::new("access_denied", "the user denied the request"),
+        })));
+    }
+    let result = issue_code(
+        app,
+        &client,
+        &pending.auth_code,
+        pending.auth_code_ttl_secs,
+        pending.response_mode,
+    )
+    .await?;
+    Ok(Some(ConsentOutcome::Approved(result)))
 }
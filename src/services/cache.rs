@@ -1,21 +1,69 @@
 use crate::state::AppState;
-use crate::{redis_get, redis_getdel, redis_set_ex};
+use crate::{
+    redis_get, redis_getdel, redis_incr, redis_set_ex, redis_set_keepttl, redis_set_nx_ex,
+};
 
-static AUTH_CODE_EXPIRATION_SECS: u64 = 10 * 60; // 10 minutes
-static ACCESS_TOKEN_EXPIRATION_SECS: u64 = 60 * 60; // 1 hour
+pub static DEVICE_CODE_EXPIRATION_SECS: u64 = 10 * 60; // 10 minutes
 
-pub async fn store_auth_code(app: &AppState, code: &str, payload: &str) -> anyhow::Result<()> {
+pub async fn store_auth_code(
+    app: &AppState,
+    code: &str,
+    payload: &str,
+    ttl_secs: u64,
+) -> anyhow::Result<()> {
     let mut conn = app.redis_client().get_async_connection().await?;
-    redis_set_ex!(conn, "auth_code", code, payload, AUTH_CODE_EXPIRATION_SECS);
+    redis_set_ex!(conn, "auth_code", code, payload, ttl_secs);
     Ok(())
 }
 
-pub async fn store_cookie(app: &AppState, name: &str, value: &str) -> anyhow::Result<()> {
+pub async fn store_cookie(
+    app: &AppState,
+    name: &str,
+    value: &str,
+    ttl_secs: u64,
+) -> anyhow::Result<()> {
     let mut conn = app.redis_client().get_async_connection().await?;
-    redis_set_ex!(conn, "cookie", name, value, ACCESS_TOKEN_EXPIRATION_SECS);
+    redis_set_ex!(conn, "cookie", name, value, ttl_secs);
     Ok(())
 }
 
+// An authorization request waiting for the user's consent, see `services::authorize`
+pub async fn store_consent(
+    app: &AppState,
+    consent_id: &str,
+    payload: &str,
+    ttl_secs: u64,
+) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_ex!(conn, "consent", consent_id, payload, ttl_secs);
+    Ok(())
+}
+
+// Consents are answered once
+pub async fn take_consent(app: &AppState, consent_id: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_getdel!(conn, "consent", consent_id);
+    Ok(v)
+}
+
+// RFC 9126 pushed authorization requests, keyed by the random part of the request_uri
+pub async fn store_pushed_request(
+    app: &AppState,
+    handle: &str,
+    request: &str,
+    ttl_secs: u64,
+) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_ex!(conn, "par", handle, request, ttl_secs);
+    Ok(())
+}
+
+pub async fn take_pushed_request(app: &AppState, handle: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_getdel!(conn, "par", handle);
+    Ok(v)
+}
+
 pub async fn redeem_code(app: &AppState, code: &str) -> anyhow::Result<Option<String>> {
     let mut conn = app.redis_client().get_async_connection().await?;
     let v = redis_getdel!(conn, "auth_code", code);
@@ -27,3 +75,198 @@ pub async fn get_cookie(app: &AppState, name: &str) -> anyhow::Result<Option<Str
     let v = redis_get!(conn, "cookie", name);
     Ok(v)
 }
+
+// The device code holds the payload, the user code only points at the device code
+pub async fn store_device_code(
+    app: &AppState,
+    device_code: &str,
+    user_code: &str,
+    payload: &str,
+) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_ex!(
+        conn,
+        "device_code",
+        device_code,
+        payload,
+        DEVICE_CODE_EXPIRATION_SECS
+    );
+    redis_set_ex!(
+        conn,
+        "user_code",
+        user_code,
+        device_code,
+        DEVICE_CODE_EXPIRATION_SECS
+    );
+    Ok(())
+}
+
+pub async fn get_device_code(app: &AppState, device_code: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_get!(conn, "device_code", device_code);
+    Ok(v)
+}
+
+pub async fn update_device_code(
+    app: &AppState,
+    device_code: &str,
+    payload: &str,
+) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_keepttl!(conn, "device_code", device_code, payload);
+    Ok(())
+}
+
+pub async fn redeem_device_code(
+    app: &AppState,
+    device_code: &str,
+) -> anyhow::Result<Option<String>> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_getdel!(conn, "device_code", device_code);
+    Ok(v)
+}
+
+// One-time lookup: once a user has acted on a user code it cannot be entered again
+pub async fn redeem_user_code(app: &AppState, user_code: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_getdel!(conn, "user_code", user_code);
+    Ok(v)
+}
+
+// false if the device already polled within the last `interval` seconds
+pub async fn register_device_poll(
+    app: &AppState,
+    device_code: &str,
+    interval: u64,
+) -> anyhow::Result<bool> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let fresh = redis_set_nx_ex!(conn, "device_poll", device_code, "1", interval);
+    Ok(fresh)
+}
+
+// Deny-list entries live exactly as long as the access token would have
+pub async fn deny_access_token(app: &AppState, jti: &str, ttl: u64) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_ex!(conn, "revoked_jti", jti, "1", ttl);
+    Ok(())
+}
+
+pub async fn is_access_token_denied(app: &AppState, jti: &str) -> anyhow::Result<bool> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v: Option<String> = redis_get!(conn, "revoked_jti", jti);
+    Ok(v.is_some())
+}
+
+// false if the client already used this assertion jti, entries expire with the assertion
+pub async fn register_client_assertion(
+    app: &AppState,
+    client_id: &str,
+    jti: &str,
+    ttl: u64,
+) -> anyhow::Result<bool> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let fresh = redis_set_nx_ex!(
+        conn,
+        "client_assertion",
+        format!("{client_id}:{jti}"),
+        "1",
+        ttl
+    );
+    Ok(fresh)
+}
+
+pub async fn store_client_jwks(
+    app: &AppState,
+    jwks_uri: &str,
+    jwks: &str,
+    ttl: u64,
+) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_ex!(conn, "client_jwks", jwks_uri, jwks, ttl);
+    Ok(())
+}
+
+// false if the jwks_uri was already refetched within the last `ttl` seconds
+pub async fn register_client_jwks_refetch(
+    app: &AppState,
+    jwks_uri: &str,
+    ttl: u64,
+) -> anyhow::Result<bool> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let fresh = redis_set_nx_ex!(conn, "client_jwks_refetch", jwks_uri, "1", ttl);
+    Ok(fresh)
+}
+
+pub async fn get_client_jwks(app: &AppState, jwks_uri: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_get!(conn, "client_jwks", jwks_uri);
+    Ok(v)
+}
+
+// Only one instance runs a key rotation step at a time, the lock expires on its own
+pub async fn acquire_key_rotation_lock(app: &AppState, ttl: u64) -> anyhow::Result<bool> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let acquired = redis_set_nx_ex!(conn, "lock", "key_rotation", "1", ttl);
+    Ok(acquired)
+}
+
+// Bumped whenever the signing_keys table changes, instances reload when it moves
+pub async fn bump_signing_keys_generation(app: &AppState) -> anyhow::Result<u64> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_incr!(conn, "signing_keys", "generation");
+    Ok(v)
+}
+
+pub async fn get_signing_keys_generation(app: &AppState) -> anyhow::Result<u64> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v: Option<u64> = redis_get!(conn, "signing_keys", "generation");
+    Ok(v.unwrap_or_default())
+}
+
+// false if a DPoP proof with this jti was already seen for the key
+pub async fn register_dpop_proof(
+    app: &AppState,
+    jkt: &str,
+    jti: &str,
+    ttl: u64,
+) -> anyhow::Result<bool> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let fresh = redis_set_nx_ex!(conn, "dpop_jti", format!("{jkt}:{jti}"), "1", ttl);
+    Ok(fresh)
+}
+
+pub async fn store_dpop_nonce(app: &AppState, nonce: &str, ttl: u64) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_ex!(conn, "dpop_nonce", nonce, "1", ttl);
+    Ok(())
+}
+
+pub async fn is_dpop_nonce_valid(app: &AppState, nonce: &str) -> anyhow::Result<bool> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v: Option<String> = redis_get!(conn, "dpop_nonce", nonce);
+    Ok(v.is_some())
+}
+
+// Opaque access tokens are keyed by the hash of the handle, the value is the serialized claims
+pub async fn store_opaque_token(
+    app: &AppState,
+    handle_hash: &str,
+    claims: &str,
+    ttl: u64,
+) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    redis_set_ex!(conn, "opaque_token", handle_hash, claims, ttl);
+    Ok(())
+}
+
+pub async fn get_opaque_token(app: &AppState, handle_hash: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let v = redis_get!(conn, "opaque_token", handle_hash);
+    Ok(v)
+}
+
+pub async fn delete_opaque_token(app: &AppState, handle_hash: &str) -> anyhow::Result<()> {
+    let mut conn = app.redis_client().get_async_connection().await?;
+    let _: Option<String> = redis_getdel!(conn, "opaque_token", handle_hash);
+    Ok(())
+}
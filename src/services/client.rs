@@ -1,4 +1,4 @@
-use crate::repositories::clients::create_client;
+use crate::repositories::clients::{create_client, ClientSettings};
 use crate::services::password::hash_password;
 use crate::state::AppState;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
@@ -11,7 +11,9 @@ pub async fn register_client_service(
     redirect_uris: &[String],
     grant_types: &[String],
     scopes: &[String],
-) -> anyhow::Result<(u64, String)> {
+    settings: &ClientSettings,
+) -> anyhow::Result<(u64, Option<String>)> {
+    // clients using keys or certificates still get a (random, never returned) secret so the column stays populated
     let mut secret_bytes = [0u8; 32]; // 256-bit
     OsRng.fill_bytes(&mut secret_bytes);
     let secret_plain = URL_SAFE_NO_PAD.encode(secret_bytes);
@@ -25,8 +27,13 @@ pub async fn register_client_service(
         redirect_uris,
         grant_types,
         scopes,
+        settings,
     )
     .await?;
 
+    let secret_plain = settings
+        .token_endpoint_auth_method
+        .uses_secret()
+        .then_some(secret_plain);
     Ok((client_id, secret_plain))
 }
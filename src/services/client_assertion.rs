@@ -0,0 +1,279 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Header, Validation};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use crate::repositories::clients::ClientSettings;
+use crate::services::cache::{
+    get_client_jwks, register_client_assertion, register_client_jwks_refetch, store_client_jwks,
+};
+use crate::state::AppState;
+
+pub static CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+static CLIENT_JWKS_CACHE_SECS: u64 = 5 * 60; // 5 minutes
+static CLIENT_JWKS_FETCH_TIMEOUT_SECS: u64 = 5;
+// An unknown kid triggers at most one refetch per jwks_uri in this window, the kid comes
+// from a token nobody has verified yet
+static CLIENT_JWKS_REFETCH_COOLDOWN_SECS: u64 = 60;
+static CLIENT_JWKS_MAX_BYTES: usize = 64 * 1024;
+
+// Endpoints that accept client assertions, any of them (or the issuer) is a valid `aud`
+static ASSERTION_ENDPOINTS: [&str; 5] = [
+    "/token",
+    "/introspect",
+    "/revoke",
+    "/device_authorization",
+    "/par",
+];
+
+#[derive(Deserialize)]
+struct AssertionClaims {
+    sub: String,
+    jti: String,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct AssertionSubject {
+    sub: Option<String>,
+}
+
+// Not verified: only tells us which client is authenticating when client_id was omitted
+pub fn assertion_subject(assertion: &str) -> Option<String> {
+    let payload = assertion.split('.').nth(1)?;
+    let bytes = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice::<AssertionSubject>(&bytes).ok()?.sub
+}
+
+async fn fetch_jwks(app: &AppState, jwks_uri: &str, use_cache: bool) -> anyhow::Result<String> {
+    if use_cache {
+        if let Some(cached) = get_client_jwks(app, jwks_uri).await? {
+            return Ok(cached);
+        }
+    }
+
+    let mut response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(CLIENT_JWKS_FETCH_TIMEOUT_SECS))
+        .build()?
+        .get(jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > CLIENT_JWKS_MAX_BYTES {
+            return Err(anyhow::anyhow!(
+                "Client jwks_uri returned more than {CLIENT_JWKS_MAX_BYTES} bytes"
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = String::from_utf8(body)?;
+
+    store_client_jwks(app, jwks_uri, &body, CLIENT_JWKS_CACHE_SECS).await?;
+    Ok(body)
+}
+
+async fn load_jwks(
+    app: &AppState,
+    settings: &ClientSettings,
+    use_cache: bool,
+) -> anyhow::Result<JwkSet> {
+    let raw = match (&settings.jwks, &settings.jwks_uri) {
+        (Some(jwks), _) => jwks.clone(),
+        (None, Some(jwks_uri)) => fetch_jwks(app, jwks_uri, use_cache).await?,
+        (None, None) => return Err(anyhow::anyhow!("Client has no registered keys")),
+    };
+    Ok(serde_json::from_str(&raw)?)
+}
+
+pub async fn client_jwks(app: &AppState, settings: &ClientSettings) -> anyhow::Result<JwkSet> {
+    load_jwks(app, settings, true).await
+}
+
+// The registered key a client signed `what` with: the one named by kid, or the only one there is
+pub async fn client_key(
+    app: &AppState,
+    settings: &ClientSettings,
+    header: &Header,
+    what: &str,
+) -> anyhow::Result<DecodingKey> {
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err(anyhow::anyhow!("{what} must be signed with a private key"));
+    }
+
+    let mut jwks = client_jwks(app, settings).await?;
+    // a kid we have not seen may be a freshly rotated key, worth a refetch now and then
+    if let (Some(jwks_uri), Some(kid)) = (&settings.jwks_uri, header.kid.as_deref()) {
+        if jwks.find(kid).is_none()
+            && register_client_jwks_refetch(app, jwks_uri, CLIENT_JWKS_REFETCH_COOLDOWN_SECS)
+                .await?
+        {
+            jwks = load_jwks(app, settings, false).await?;
+        }
+    }
+    let jwk = match header.kid.as_deref() {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| anyhow::anyhow!("No registered key matches the {what}"))?;
+    if let Some(key_alg) = jwk.common.key_algorithm {
+        if Algorithm::from_str(&key_alg.to_string()).ok() != Some(header.alg) {
+            return Err(anyhow::anyhow!("{what} alg does not match the key"));
+        }
+    }
+
+    Ok(DecodingKey::from_jwk(jwk)?)
+}
+
+// Loom's issuer, its base URL or the URL of an endpoint that accepts assertions
+fn assertion_audience(issuer: &str, base_url: &str) -> Vec<String> {
+    [issuer.to_string(), base_url.to_string()]
+        .into_iter()
+        .chain(
+            ASSERTION_ENDPOINTS
+                .iter()
+                .map(|path| format!("{base_url}{path}")),
+        )
+        .collect()
+}
+
+// Signature, exp, iss, sub and aud; replays are checked by the caller
+fn decode_assertion(
+    assertion: &str,
+    key: &DecodingKey,
+    alg: Algorithm,
+    client_id: &str,
+    audience: &[String],
+) -> anyhow::Result<AssertionClaims> {
+    let mut validation = Validation::new(alg);
+    validation.set_audience(audience);
+    validation.set_issuer(&[client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "sub", "aud"]);
+
+    let claims = decode::<AssertionClaims>(assertion, key, &validation)?.claims;
+    if claims.sub != client_id {
+        return Err(anyhow::anyhow!(
+            "client_assertion sub must be the client_id"
+        ));
+    }
+    Ok(claims)
+}
+
+/*
+ * RFC 7523 section 3: iss and sub are the client_id, aud is Loom, exp is required
+ * and the jti can only be used once (kept in redis until the assertion expires).
+ */
+pub async fn verify_client_assertion(
+    app: &AppState,
+    client_id: &str,
+    settings: &ClientSettings,
+    assertion: &str,
+) -> anyhow::Result<()> {
+    let header = decode_header(assertion)?;
+    let key = client_key(app, settings, &header, "client_assertion").await?;
+
+    let audience = assertion_audience(app.issuer(), app.base_url());
+    let claims = decode_assertion(assertion, &key, header.alg, client_id, &audience)?;
+
+    let remaining = claims.exp - OffsetDateTime::now_utc().unix_timestamp();
+    let ttl = u64::try_from(remaining).unwrap_or_default().max(1);
+    if !register_client_assertion(app, client_id, &claims.jti, ttl).await? {
+        return Err(anyhow::anyhow!("client_assertion has already been used"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"test secret";
+    const CLIENT_ID: &str = "client";
+
+    fn audience() -> Vec<String> {
+        assertion_audience("https://issuer.example", "https://loom.example")
+    }
+
+    fn sign(claims: &serde_json::Value) -> String {
+        encode(
+            &Header::new(Algorithm::HS256),
+            claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn claims(iss: &str, sub: &str, aud: &str) -> serde_json::Value {
+        json!({
+            "iss": iss,
+            "sub": sub,
+            "aud": aud,
+            "jti": "jti",
+            "exp": OffsetDateTime::now_utc().unix_timestamp() + 60,
+        })
+    }
+
+    fn check(assertion: &str) -> anyhow::Result<AssertionClaims> {
+        decode_assertion(
+            assertion,
+            &DecodingKey::from_secret(SECRET),
+            Algorithm::HS256,
+            CLIENT_ID,
+            &audience(),
+        )
+    }
+
+    #[test]
+    fn issuer_base_url_and_endpoints_are_audiences() {
+        for aud in [
+            "https://issuer.example",
+            "https://loom.example",
+            "https://loom.example/token",
+            "https://loom.example/par",
+        ] {
+            assert!(
+                check(&sign(&claims(CLIENT_ID, CLIENT_ID, aud))).is_ok(),
+                "{aud} was refused"
+            );
+        }
+    }
+
+    #[test]
+    fn other_audiences_are_rejected() {
+        let assertion = sign(&claims(CLIENT_ID, CLIENT_ID, "https://other.example/token"));
+        assert!(check(&assertion).is_err());
+    }
+
+    #[test]
+    fn iss_and_sub_must_be_the_client() {
+        let aud = "https://loom.example/token";
+        assert!(check(&sign(&claims("other", CLIENT_ID, aud))).is_err());
+        assert!(check(&sign(&claims(CLIENT_ID, "other", aud))).is_err());
+    }
+
+    #[test]
+    fn exp_is_required() {
+        let mut without_exp = claims(CLIENT_ID, CLIENT_ID, "https://loom.example/token");
+        without_exp.as_object_mut().unwrap().remove("exp");
+        assert!(check(&sign(&without_exp)).is_err());
+    }
+
+    #[test]
+    fn subject_is_read_without_verification() {
+        let assertion = sign(&claims(CLIENT_ID, "named", "https://loom.example"));
+        assert_eq!(assertion_subject(&assertion).as_deref(), Some("named"));
+        assert_eq!(assertion_subject("garbage"), None);
+    }
+}
@@ -0,0 +1,204 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::{rngs::OsRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::services::cache::{
+    get_device_code, redeem_device_code, redeem_user_code, register_device_poll, store_device_code,
+    update_device_code, DEVICE_CODE_EXPIRATION_SECS,
+};
+use crate::state::AppState;
+
+pub static DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+pub static DEVICE_POLL_INTERVAL_SECS: u64 = 5;
+
+// RFC 8628 section 6.1: no vowels (no accidental words) and no ambiguous characters
+static USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+static USER_CODE_LEN: usize = 8;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceCodeStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceCodePayload {
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    pub status: DeviceCodeStatus,
+    pub user_id: Option<String>,
+}
+
+pub struct DeviceAuthorization {
+    pub device_code: String,
+    pub user_code: String,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+// Why a poll got no token
+pub enum DevicePollError {
+    Pending,
+    SlowDown,
+    Denied,
+    // never issued, already redeemed, or expired: redis drops the code with its TTL
+    Unknown,
+    ClientMismatch,
+}
+
+fn generate_device_code() -> String {
+    let mut bytes = [0u8; 32]; // 256-bit
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn generate_user_code() -> String {
+    (0..USER_CODE_LEN)
+        .map(|_| char::from(USER_CODE_ALPHABET[OsRng.gen_range(0..USER_CODE_ALPHABET.len())]))
+        .collect()
+}
+
+// Users type the code by hand: ignore case, dashes and spaces
+pub fn normalize_user_code(input: &str) -> String {
+    input
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// Displayed as XXXX-XXXX
+pub fn format_user_code(user_code: &str) -> String {
+    let (head, tail) = user_code.split_at(user_code.len() / 2);
+    format!("{head}-{tail}")
+}
+
+pub async fn start_device_authorization(
+    app: &AppState,
+    client_id: &str,
+    scopes: Vec<String>,
+) -> anyhow::Result<DeviceAuthorization> {
+    let device_code = generate_device_code();
+    let user_code = generate_user_code();
+
+    let payload = DeviceCodePayload {
+        client_id: client_id.to_string(),
+        scopes,
+        status: DeviceCodeStatus::Pending,
+        user_id: None,
+    };
+
+    store_device_code(
+        app,
+        &device_code,
+        &user_code,
+        serde_json::to_string(&payload)?.as_str(),
+    )
+    .await?;
+
+    Ok(DeviceAuthorization {
+        device_code,
+        user_code,
+        expires_in: DEVICE_CODE_EXPIRATION_SECS,
+        interval: DEVICE_POLL_INTERVAL_SECS,
+    })
+}
+
+/*
+ * Called from the verification page once the user is logged in.
+ * Returns false if the user code is unknown, expired or was already used.
+ */
+pub async fn complete_device_authorization(
+    app: &AppState,
+    user_code: &str,
+    user_id: &str,
+    approve: bool,
+) -> anyhow::Result<bool> {
+    let Some(device_code) = redeem_user_code(app, &normalize_user_code(user_code)).await? else {
+        return Ok(false);
+    };
+
+    let Some(serialized) = get_device_code(app, &device_code).await? else {
+        return Ok(false);
+    };
+
+    let mut payload: DeviceCodePayload = serde_json::from_str(&serialized)?;
+    if payload.status != DeviceCodeStatus::Pending {
+        return Ok(false);
+    }
+
+    if approve {
+        payload.status = DeviceCodeStatus::Approved;
+        payload.user_id = Some(user_id.to_string());
+    } else {
+        payload.status = DeviceCodeStatus::Denied;
+    }
+
+    update_device_code(app, &device_code, serde_json::to_string(&payload)?.as_str()).await?;
+    Ok(true)
+}
+
+// Token endpoint side of the flow, see RFC 8628 section 3.5
+pub async fn poll_device_code(
+    app: &AppState,
+    client_id: &str,
+    device_code: &str,
+) -> anyhow::Result<Result<DeviceCodePayload, DevicePollError>> {
+    let Some(serialized) = get_device_code(app, device_code).await? else {
+        return Ok(Err(DevicePollError::Unknown));
+    };
+
+    let payload: DeviceCodePayload = serde_json::from_str(&serialized)?;
+    if payload.client_id != client_id {
+        return Ok(Err(DevicePollError::ClientMismatch));
+    }
+
+    match payload.status {
+        DeviceCodeStatus::Pending => {
+            if register_device_poll(app, device_code, DEVICE_POLL_INTERVAL_SECS).await? {
+                Ok(Err(DevicePollError::Pending))
+            } else {
+                Ok(Err(DevicePollError::SlowDown))
+            }
+        }
+        DeviceCodeStatus::Denied => {
+            redeem_device_code(app, device_code).await?;
+            Ok(Err(DevicePollError::Denied))
+        }
+        // take it out of redis so a second poll cannot mint another token
+        DeviceCodeStatus::Approved => match redeem_device_code(app, device_code).await? {
+            Some(serialized) => Ok(Ok(serde_json::from_str(&serialized)?)),
+            None => Ok(Err(DevicePollError::Unknown)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_codes_use_the_unambiguous_alphabet() {
+        let user_code = generate_user_code();
+        assert_eq!(user_code.len(), USER_CODE_LEN);
+        assert!(user_code.bytes().all(|c| USER_CODE_ALPHABET.contains(&c)));
+    }
+
+    #[test]
+    fn typed_user_codes_are_normalized() {
+        assert_eq!(normalize_user_code("bcdf-ghjk"), "BCDFGHJK");
+        assert_eq!(normalize_user_code(" BCDF GHJK "), "BCDFGHJK");
+        assert_eq!(
+            normalize_user_code(&format_user_code("BCDFGHJK")),
+            "BCDFGHJK"
+        );
+    }
+
+    #[test]
+    fn user_codes_are_displayed_in_two_halves() {
+        assert_eq!(format_user_code("BCDFGHJK"), "BCDF-GHJK");
+    }
+}
@@ -0,0 +1,269 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::services::cache::{is_dpop_nonce_valid, register_dpop_proof, store_dpop_nonce};
+use crate::state::AppState;
+
+// How far a proof's iat may be from our clock, in either direction
+static DPOP_PROOF_MAX_AGE_SECS: u64 = 60;
+static DPOP_NONCE_EXPIRATION_SECS: u64 = 5 * 60; // 5 minutes
+
+#[derive(Deserialize)]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProofHeaderKey {
+    jwk: serde_json::Map<String, serde_json::Value>,
+}
+
+pub enum DpopCheck {
+    // the JWK thumbprint the issued tokens get bound to
+    Valid(String),
+    // well-formed, but without a current server nonce (RFC 9449 section 8)
+    UseNonce,
+    Invalid(&'static str),
+}
+
+fn curve_name(curve: &EllipticCurve) -> &'static str {
+    match curve {
+        EllipticCurve::P256 => "P-256",
+        EllipticCurve::P384 => "P-384",
+        EllipticCurve::P521 => "P-521",
+        EllipticCurve::Ed25519 => "Ed25519",
+    }
+}
+
+// RFC 7638: SHA-256 over the required members in lexicographic order, no whitespace
+fn jwk_thumbprint(jwk: &Jwk) -> Option<String> {
+    let canonical = match &jwk.algorithm {
+        AlgorithmParameters::RSA(rsa) => {
+            format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n)
+        }
+        AlgorithmParameters::EllipticCurve(ec) => format!(
+            r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#,
+            curve_name(&ec.curve),
+            ec.x,
+            ec.y
+        ),
+        AlgorithmParameters::OctetKeyPair(okp) => format!(
+            r#"{{"crv":"{}","kty":"OKP","x":"{}"}}"#,
+            curve_name(&okp.curve),
+            okp.x
+        ),
+        AlgorithmParameters::OctetKey(_) => return None,
+    };
+    Some(URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes())))
+}
+
+// The jwk header must be a public key, a proof carrying the private part is rejected
+fn has_private_key(proof: &str) -> bool {
+    proof
+        .split('.')
+        .next()
+        .and_then(|header| URL_SAFE_NO_PAD.decode(header).ok())
+        .and_then(|bytes| serde_json::from_slice::<ProofHeaderKey>(&bytes).ok())
+        .is_none_or(|header| header.jwk.contains_key("d"))
+}
+
+// The proof was made for this request (htm, htu) and just now (iat, `now` in unix seconds)
+fn check_claims(claims: &ProofClaims, htm: &str, htu: &str, now: i64) -> Result<(), &'static str> {
+    if claims.htm != htm {
+        return Err("DPoP proof htm does not match the request");
+    }
+    if claims.htu.split(['?', '#']).next() != Some(htu) {
+        return Err("DPoP proof htu does not match the request");
+    }
+    if claims.iat.abs_diff(now) > DPOP_PROOF_MAX_AGE_SECS {
+        return Err("DPoP proof iat is too far from the current time");
+    }
+    Ok(())
+}
+
+pub async fn issue_dpop_nonce(app: &AppState) -> anyhow::Result<String> {
+    let mut bytes = [0u8; 16]; // 128-bit
+    OsRng.fill_bytes(&mut bytes);
+    let nonce = URL_SAFE_NO_PAD.encode(bytes);
+    store_dpop_nonce(app, &nonce, DPOP_NONCE_EXPIRATION_SECS).await?;
+    Ok(nonce)
+}
+
+/*
+ * RFC 9449 section 4.3: a DPoP proof is a JWT signed with the key the tokens get bound to.
+ * It must be made for this request (htm, htu), be fresh (iat), carry a nonce we issued
+ * and never be replayed (jti, remembered in redis for as long as the proof is acceptable).
+ * `htu` is the request URL without query and fragment.
+ */
+pub async fn verify_dpop_proof(
+    app: &AppState,
+    proof: &str,
+    htm: &str,
+    htu: &str,
+) -> anyhow::Result<DpopCheck> {
+    let Ok(header) = decode_header(proof) else {
+        return Ok(DpopCheck::Invalid("DPoP proof is not a JWT"));
+    };
+    if header.typ.as_deref() != Some("dpop+jwt") {
+        return Ok(DpopCheck::Invalid("DPoP proof typ must be dpop+jwt"));
+    }
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Ok(DpopCheck::Invalid(
+            "DPoP proof must be signed with an asymmetric key",
+        ));
+    }
+    let Some(jwk) = header.jwk else {
+        return Ok(DpopCheck::Invalid("DPoP proof has no jwk header"));
+    };
+    if has_private_key(proof) {
+        return Ok(DpopCheck::Invalid("DPoP proof jwk must be a public key"));
+    }
+    let (Some(jkt), Ok(key)) = (jwk_thumbprint(&jwk), DecodingKey::from_jwk(&jwk)) else {
+        return Ok(DpopCheck::Invalid("DPoP proof jwk is not supported"));
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.set_required_spec_claims::<&str>(&[]);
+    let Ok(data) = decode::<ProofClaims>(proof, &key, &validation) else {
+        return Ok(DpopCheck::Invalid(
+            "DPoP proof signature or claims are invalid",
+        ));
+    };
+    let claims = data.claims;
+
+    if let Err(reason) = check_claims(
+        &claims,
+        htm,
+        htu,
+        OffsetDateTime::now_utc().unix_timestamp(),
+    ) {
+        return Ok(DpopCheck::Invalid(reason));
+    }
+
+    match claims.nonce.as_deref() {
+        Some(nonce) if is_dpop_nonce_valid(app, nonce).await? => {}
+        _ => return Ok(DpopCheck::UseNonce),
+    }
+
+    // a replay is only possible while the iat is acceptable
+    if !register_dpop_proof(app, &jkt, &claims.jti, 2 * DPOP_PROOF_MAX_AGE_SECS).await? {
+        return Ok(DpopCheck::Invalid("DPoP proof has already been used"));
+    }
+
+    Ok(DpopCheck::Valid(jkt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jwk_thumbprint_matches_rfc7638_example() {
+        // RFC 7638 section 3.1
+        let jwk: Jwk = serde_json::from_value(serde_json::json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29"
+        }))
+        .unwrap();
+        assert_eq!(
+            jwk_thumbprint(&jwk).as_deref(),
+            Some("NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs")
+        );
+    }
+
+    const HTU: &str = "https://loom.example/token";
+    const NOW: i64 = 1_700_000_000;
+
+    fn claims(htm: &str, htu: &str, iat: i64) -> ProofClaims {
+        ProofClaims {
+            jti: "jti".to_string(),
+            htm: htm.to_string(),
+            htu: htu.to_string(),
+            iat,
+            nonce: None,
+        }
+    }
+
+    #[test]
+    fn claims_for_this_request_are_accepted() {
+        assert_eq!(
+            check_claims(&claims("POST", HTU, NOW), "POST", HTU, NOW),
+            Ok(())
+        );
+        // query and fragment are not part of the comparison
+        let with_query = format!("{HTU}?x=1#y");
+        assert_eq!(
+            check_claims(&claims("POST", &with_query, NOW), "POST", HTU, NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn claims_for_another_request_are_rejected() {
+        assert_eq!(
+            check_claims(&claims("GET", HTU, NOW), "POST", HTU, NOW),
+            Err("DPoP proof htm does not match the request")
+        );
+        assert_eq!(
+            check_claims(
+                &claims("POST", "https://loom.example/revoke", NOW),
+                "POST",
+                HTU,
+                NOW
+            ),
+            Err("DPoP proof htu does not match the request")
+        );
+    }
+
+    #[test]
+    fn iat_must_be_close_to_now() {
+        let max_age = i64::try_from(DPOP_PROOF_MAX_AGE_SECS).unwrap();
+        for iat in [NOW - max_age, NOW + max_age] {
+            assert_eq!(
+                check_claims(&claims("POST", HTU, iat), "POST", HTU, NOW),
+                Ok(())
+            );
+        }
+        for iat in [NOW - max_age - 1, NOW + max_age + 1] {
+            assert_eq!(
+                check_claims(&claims("POST", HTU, iat), "POST", HTU, NOW),
+                Err("DPoP proof iat is too far from the current time")
+            );
+        }
+    }
+
+    fn proof_with_header(header: &serde_json::Value) -> String {
+        format!("{}.e30.sig", URL_SAFE_NO_PAD.encode(header.to_string()))
+    }
+
+    #[test]
+    fn only_public_jwks_are_accepted() {
+        let public = serde_json::json!({ "kty": "OKP", "crv": "Ed25519", "x": "x" });
+        let mut private = public.clone();
+        private["d"] = serde_json::json!("d");
+
+        let header = |jwk| serde_json::json!({ "typ": "dpop+jwt", "alg": "EdDSA", "jwk": jwk });
+        assert!(!has_private_key(&proof_with_header(&header(public))));
+        assert!(has_private_key(&proof_with_header(&header(private))));
+        // a header that cannot be read is not trusted either
+        assert!(has_private_key("not a jwt"));
+    }
+}
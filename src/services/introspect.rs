@@ -0,0 +1,201 @@
+use serde::Serialize;
+
+use crate::repositories::refresh_tokens::{get_by_token_hash, RefreshToken};
+use crate::services::refresh_token::hash_refresh_token;
+use crate::services::token::{verify_access_token, Confirmation};
+use crate::state::AppState;
+
+// RFC 7662 section 2.2, inactive tokens only carry `active: false`
+#[derive(Debug, Default, Serialize)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'static str>,
+    // RFC 9396 section 9.2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization_details: Option<serde_json::Value>,
+    // RFC 8705 section 3.2, the certificate a bound token must be presented with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+impl Introspection {
+    fn inactive() -> Self {
+        Introspection::default()
+    }
+}
+
+async fn introspect_access_token(app: &AppState, token: &str) -> Option<Introspection> {
+    // anything that does not verify (or was revoked) is simply not an active access token
+    let claims = verify_access_token(app, token).await.ok()?;
+
+    Some(Introspection {
+        active: true,
+        scope: Some(claims.scope),
+        client_id: Some(claims.client_id),
+        sub: Some(claims.sub),
+        exp: Some(claims.exp),
+        iat: Some(claims.iat),
+        nbf: Some(claims.nbf),
+        aud: Some(claims.aud),
+        iss: Some(claims.iss),
+        jti: Some(claims.jti),
+        token_type: Some(Confirmation::token_type(claims.cnf.as_ref())),
+        authorization_details: claims.authorization_details,
+        cnf: claims.cnf,
+    })
+}
+
+async fn introspect_refresh_token(
+    app: &AppState,
+    client_id_ref: u64,
+    token: &str,
+) -> anyhow::Result<Option<Introspection>> {
+    let Some(rt) = get_by_token_hash(app.pool(), &hash_refresh_token(token)).await? else {
+        return Ok(None);
+    };
+
+    Ok(Some(refresh_token_introspection(rt, client_id_ref)))
+}
+
+// Refresh tokens are only disclosed to the client holding them
+fn refresh_token_introspection(rt: RefreshToken, client_id_ref: u64) -> Introspection {
+    if rt.client_id_ref != client_id_ref || rt.expired || rt.rotated || rt.revoked {
+        return Introspection::inactive();
+    }
+
+    Introspection {
+        active: true,
+        scope: Some(rt.scope),
+        client_id: Some(rt.client_id),
+        sub: Some(rt.user_id),
+        exp: Some(rt.expires_at),
+        iat: Some(rt.issued_at),
+        token_type: Some("refresh_token"),
+        authorization_details: rt
+            .authorization_details
+            .as_deref()
+            .and_then(|details| serde_json::from_str(details).ok()),
+        ..Introspection::default()
+    }
+}
+
+/*
+ * The hint only decides which kind of token is tried first, see RFC 7662 section 2.1.
+ * The caller has authenticated the client, `client_id_ref` is its row id.
+ */
+pub async fn introspect(
+    app: &AppState,
+    client_id_ref: u64,
+    token: &str,
+    token_type_hint: Option<&str>,
+) -> anyhow::Result<Introspection> {
+    if token_type_hint == Some("refresh_token") {
+        if let Some(found) = introspect_refresh_token(app, client_id_ref, token).await? {
+            return Ok(found);
+        }
+        return Ok(introspect_access_token(app, token)
+            .await
+            .unwrap_or_else(Introspection::inactive));
+    }
+
+    if let Some(found) = introspect_access_token(app, token).await {
+        return Ok(found);
+    }
+    Ok(introspect_refresh_token(app, client_id_ref, token)
+        .await?
+        .unwrap_or_else(Introspection::inactive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const CLIENT: u64 = 7;
+
+    fn token() -> RefreshToken {
+        RefreshToken {
+            id: 1,
+            family_id: "family".to_string(),
+            client_id_ref: CLIENT,
+            client_id: "client".to_string(),
+            user_id: "user".to_string(),
+            scope: "read".to_string(),
+            authorization_details: Some(r#"[{"type":"payment_initiation"}]"#.to_string()),
+            dpop_jkt: None,
+            issued_at: 1_700_000_000,
+            expires_at: 1_700_003_600,
+            family_remaining_secs: 3600,
+            expired: false,
+            rotated: false,
+            revoked: false,
+        }
+    }
+
+    #[test]
+    fn inactive_tokens_only_say_so() {
+        assert_eq!(
+            serde_json::to_value(Introspection::inactive()).unwrap(),
+            json!({ "active": false })
+        );
+    }
+
+    #[test]
+    fn a_current_refresh_token_is_described() {
+        let introspection = refresh_token_introspection(token(), CLIENT);
+        assert_eq!(
+            serde_json::to_value(introspection).unwrap(),
+            json!({
+                "active": true,
+                "scope": "read",
+                "client_id": "client",
+                "sub": "user",
+                "exp": 1_700_003_600,
+                "iat": 1_700_000_000,
+                "token_type": "refresh_token",
+                "authorization_details": [{ "type": "payment_initiation" }]
+            })
+        );
+    }
+
+    #[test]
+    fn unusable_or_foreign_refresh_tokens_are_inactive() {
+        let tokens = [
+            RefreshToken {
+                expired: true,
+                ..token()
+            },
+            RefreshToken {
+                rotated: true,
+                ..token()
+            },
+            RefreshToken {
+                revoked: true,
+                ..token()
+            },
+        ];
+        for rt in tokens {
+            assert!(!refresh_token_introspection(rt, CLIENT).active);
+        }
+        assert!(!refresh_token_introspection(token(), CLIENT + 1).active);
+    }
+}
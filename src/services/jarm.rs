@@ -0,0 +1,124 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet, PublicKeyUse, RSAKeyParameters};
+use jsonwebtoken::{encode, Header};
+use rand::{rngs::OsRng, RngCore};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use rsa::{BigUint, Oaep, RsaPublicKey};
+use serde_json::json;
+use sha2::Sha256;
+use time::OffsetDateTime;
+
+use crate::repositories::clients::Client;
+use crate::services::client_assertion::client_jwks;
+use crate::services::keys::current_signing_key;
+use crate::state::AppState;
+
+// JARM section 2.1 asks for a short lifetime, the response is used right away
+static AUTHORIZATION_RESPONSE_LIFETIME_SECS: i64 = 10 * 60; // 10 minutes
+
+// The one JWE algorithm pair Loom encrypts with
+pub static ENCRYPTION_ALG: &str = "RSA-OAEP-256";
+pub static ENCRYPTION_ENC: &str = "A256GCM";
+
+/*
+ * JARM section 2.1: the authorization response parameters plus iss, aud (the client) and exp,
+ * signed with Loom's current key and, when the client registered authorization response
+ * encryption, encrypted to the client's key (section 2.2, signed then encrypted).
+ */
+pub async fn authorization_response(
+    app: &AppState,
+    client: &Client,
+    params: &[(&str, &str)],
+) -> anyhow::Result<String> {
+    let mut claims: serde_json::Map<String, serde_json::Value> = params
+        .iter()
+        .map(|(name, value)| ((*name).to_string(), json!(value)))
+        .collect();
+    claims.insert("iss".to_string(), json!(app.issuer()));
+    claims.insert("aud".to_string(), json!(client.name));
+    claims.insert(
+        "exp".to_string(),
+        json!(OffsetDateTime::now_utc().unix_timestamp() + AUTHORIZATION_RESPONSE_LIFETIME_SECS),
+    );
+
+    let key = current_signing_key(app)?;
+    let header = Header {
+        kid: Some(key.kid.clone()),
+        ..Header::new(key.alg)
+    };
+    let signed = encode(&header, &claims, key.encoding_key())?;
+
+    if client
+        .settings
+        .authorization_encrypted_response_alg
+        .is_none()
+    {
+        return Ok(signed);
+    }
+    // the key decides who can read the code, never take it from a plaintext jwks_uri
+    if client
+        .settings
+        .jwks_uri
+        .as_deref()
+        .is_some_and(|uri| !uri.starts_with("https://"))
+    {
+        return Err(anyhow::anyhow!("Client jwks_uri must use https"));
+    }
+    let jwks = client_jwks(app, &client.settings).await?;
+    encrypt(&jwks, &signed)
+}
+
+// An RSA key of the client's that is not only meant for signatures
+pub fn encryption_key(jwks: &JwkSet) -> Option<(Option<&str>, &RSAKeyParameters)> {
+    jwks.keys.iter().find_map(|jwk| match &jwk.algorithm {
+        AlgorithmParameters::RSA(rsa)
+            if !matches!(jwk.common.public_key_use, Some(PublicKeyUse::Signature)) =>
+        {
+            Some((jwk.common.key_id.as_deref(), rsa))
+        }
+        _ => None,
+    })
+}
+
+// RFC 7516 compact serialization, RSA-OAEP-256 wraps a random A256GCM key
+fn encrypt(jwks: &JwkSet, payload: &str) -> anyhow::Result<String> {
+    let Some((kid, rsa)) = encryption_key(jwks) else {
+        return Err(anyhow::anyhow!("Client has no RSA key to encrypt to"));
+    };
+    let public_key = RsaPublicKey::new(
+        BigUint::from_bytes_be(&URL_SAFE_NO_PAD.decode(&rsa.n)?),
+        BigUint::from_bytes_be(&URL_SAFE_NO_PAD.decode(&rsa.e)?),
+    )?;
+
+    let mut header = json!({ "alg": ENCRYPTION_ALG, "enc": ENCRYPTION_ENC, "cty": "JWT" });
+    if let Some(kid) = kid {
+        header["kid"] = json!(kid);
+    }
+    let protected = URL_SAFE_NO_PAD.encode(header.to_string());
+
+    let mut cek = [0u8; 32];
+    OsRng.fill_bytes(&mut cek);
+    let mut iv = [0u8; 12];
+    OsRng.fill_bytes(&mut iv);
+    let encrypted_key = public_key.encrypt(&mut OsRng, Oaep::new::<Sha256>(), &cek)?;
+
+    let key = UnboundKey::new(&AES_256_GCM, &cek)
+        .map_err(|_| anyhow::anyhow!("Could not create the content encryption key"))?;
+    let mut content = payload.as_bytes().to_vec();
+    let tag = LessSafeKey::new(key)
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(iv),
+            Aad::from(protected.as_bytes()),
+            &mut content,
+        )
+        .map_err(|_| anyhow::anyhow!("Encrypting the authorization response failed"))?;
+
+    Ok(format!(
+        "{protected}.{}.{}.{}.{}",
+        URL_SAFE_NO_PAD.encode(encrypted_key),
+        URL_SAFE_NO_PAD.encode(iv),
+        URL_SAFE_NO_PAD.encode(content),
+        URL_SAFE_NO_PAD.encode(tag.as_ref())
+    ))
+}
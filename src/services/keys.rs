@@ -0,0 +1,526 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use core::fmt;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{Algorithm, EncodingKey};
+use rand::{rngs::OsRng, RngCore};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rsa::pkcs1::EncodeRsaPrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::repositories::signing_keys::{
+    activate_signing_key, create_signing_key, delete_retired_signing_keys,
+    get_published_signing_keys, get_signing_key_schedule, SigningKeyRow, SigningKeySchedule,
+};
+use crate::services::cache::{
+    acquire_key_rotation_lock, bump_signing_keys_generation, get_signing_keys_generation,
+};
+use crate::services::lifetimes::MAX_ACCESS_TOKEN_LIFETIME_SECS;
+use crate::state::AppState;
+
+static RSA_KEY_BITS: usize = 2048;
+
+// Resource servers cache the key set for this long
+pub static JWKS_MAX_AGE_SECS: u64 = 5 * 60;
+// How often every instance runs the rotation step and picks up key changes
+static KEY_ROTATION_CHECK_SECS: u64 = 60;
+// A pending key is published for a full JWKS cache lifetime (plus one reload) before it signs anything
+static KEY_PUBLISH_DELAY_SECS: u64 = JWKS_MAX_AGE_SECS + KEY_ROTATION_CHECK_SECS;
+// Instances may sign with a retired key until their next reload, tokens then live as long as
+// the longest lifetime any client may have
+static RETIRED_KEY_GRACE_SECS: u64 = MAX_ACCESS_TOKEN_LIFETIME_SECS + KEY_ROTATION_CHECK_SECS;
+
+#[derive(Debug)]
+pub enum KeyRotation {
+    // another instance holds the rotation lock
+    Busy,
+    Unchanged,
+    Scheduled(String),
+    Activated(String),
+}
+
+pub struct SigningKey {
+    pub kid: String,
+    pub alg: Algorithm,
+    encoding_key: EncodingKey,
+}
+
+// never print key material
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("kid", &self.kid)
+            .field("alg", &self.alg)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKey {
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    fn from_row(row: &SigningKeyRow, encryption: Option<&LessSafeKey>) -> anyhow::Result<Self> {
+        let alg = parse_signing_alg(&row.alg)?;
+        let der = open_private_key(row, encryption)?;
+        let encoding_key = match alg {
+            Algorithm::RS256 => EncodingKey::from_rsa_der(&der),
+            Algorithm::ES256 => EncodingKey::from_ec_der(&der),
+            _ => EncodingKey::from_ed_der(&der),
+        };
+
+        Ok(SigningKey {
+            kid: row.kid.clone(),
+            alg,
+            encoding_key,
+        })
+    }
+}
+
+// In-memory copy of the signing_keys table, newest first
+#[derive(Debug, Default)]
+pub struct KeyStore {
+    // redis generation the keys were loaded at
+    generation: u64,
+    keys: Vec<Arc<SigningKey>>,
+    // public halves of the pending, active and recently retired keys, served as JWKS
+    published: Vec<Jwk>,
+}
+
+impl KeyStore {
+    pub fn signing_key(&self, alg: Algorithm) -> Option<Arc<SigningKey>> {
+        self.keys.iter().find(|k| k.alg == alg).cloned()
+    }
+
+    pub fn verification_key(&self, kid: &str) -> Option<Jwk> {
+        self.published
+            .iter()
+            .find(|jwk| jwk.common.key_id.as_deref() == Some(kid))
+            .cloned()
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.published.clone(),
+        }
+    }
+}
+
+pub fn parse_signing_alg(alg: &str) -> anyhow::Result<Algorithm> {
+    match Algorithm::from_str(alg) {
+        Ok(alg @ (Algorithm::RS256 | Algorithm::ES256 | Algorithm::EdDSA)) => Ok(alg),
+        _ => Err(anyhow::anyhow!(
+            "Unsupported signing algorithm {alg}, expected RS256, ES256 or EdDSA"
+        )),
+    }
+}
+
+// SIGNING_KEY_ENCRYPTION_KEY: 32 random bytes, base64
+pub fn signing_key_encryption(key: &str) -> anyhow::Result<LessSafeKey> {
+    let bytes = STANDARD
+        .decode(key.trim())
+        .map_err(|_| anyhow::anyhow!("SIGNING_KEY_ENCRYPTION_KEY must be base64"))?;
+    let key = UnboundKey::new(&AES_256_GCM, &bytes)
+        .map_err(|_| anyhow::anyhow!("SIGNING_KEY_ENCRYPTION_KEY must be 32 bytes"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+// AES-256-GCM, stored as nonce || ciphertext || tag; the kid is bound as associated data so a
+// sealed key cannot be moved to another row
+fn seal_private_key(encryption: &LessSafeKey, kid: &str, der: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let mut sealed = der.to_vec();
+    encryption
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(kid.as_bytes()),
+            &mut sealed,
+        )
+        .map_err(|_| anyhow::anyhow!("Encrypting signing key {kid} failed"))?;
+    Ok([nonce.as_slice(), &sealed].concat())
+}
+
+fn open_private_key(
+    row: &SigningKeyRow,
+    encryption: Option<&LessSafeKey>,
+) -> anyhow::Result<Vec<u8>> {
+    if !row.private_key_encrypted {
+        return Ok(row.private_key.clone());
+    }
+    let Some(encryption) = encryption else {
+        return Err(anyhow::anyhow!(
+            "Signing key {} is encrypted, but SIGNING_KEY_ENCRYPTION_KEY is not set",
+            row.kid
+        ));
+    };
+    if row.private_key.len() < NONCE_LEN {
+        return Err(anyhow::anyhow!("Signing key {} is truncated", row.kid));
+    }
+    let (nonce, sealed) = row.private_key.split_at(NONCE_LEN);
+    let mut sealed = sealed.to_vec();
+    let der = encryption
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce)
+                .map_err(|_| anyhow::anyhow!("Signing key {} is truncated", row.kid))?,
+            Aad::from(row.kid.as_bytes()),
+            &mut sealed,
+        )
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Signing key {} could not be decrypted, is SIGNING_KEY_ENCRYPTION_KEY the one it was created with?",
+                row.kid
+            )
+        })?;
+    Ok(der.to_vec())
+}
+
+fn alg_name(alg: Algorithm) -> &'static str {
+    match alg {
+        Algorithm::RS256 => "RS256",
+        Algorithm::ES256 => "ES256",
+        _ => "EdDSA",
+    }
+}
+
+fn generate_kid() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Returns the private key DER (as expected by `EncodingKey::from_*_der`) and the public JWK params
+fn generate_key_material(alg: Algorithm) -> anyhow::Result<(Vec<u8>, AlgorithmParameters)> {
+    match alg {
+        Algorithm::RS256 => {
+            let key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)?;
+            let der = key.to_pkcs1_der()?.as_bytes().to_vec();
+            let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            });
+            Ok((der, params))
+        }
+        Algorithm::ES256 => {
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| anyhow::anyhow!("Failed to generate P-256 key"))?;
+            let pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                    .map_err(|e| anyhow::anyhow!("Generated P-256 key rejected: {e}"))?;
+            // uncompressed point: 0x04 || x || y
+            let point = pair.public_key().as_ref();
+            let params = AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+                y: URL_SAFE_NO_PAD.encode(&point[33..65]),
+            });
+            Ok((pkcs8.as_ref().to_vec(), params))
+        }
+        Algorithm::EdDSA => {
+            let rng = SystemRandom::new();
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+                .map_err(|_| anyhow::anyhow!("Failed to generate Ed25519 key"))?;
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                .map_err(|e| anyhow::anyhow!("Generated Ed25519 key rejected: {e}"))?;
+            let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+            });
+            Ok((pkcs8.as_ref().to_vec(), params))
+        }
+        other => Err(anyhow::anyhow!("Unsupported signing algorithm {other:?}")),
+    }
+}
+
+// Returns the new key's row id and kid
+async fn create_key(app: &AppState, alg: Algorithm, status: &str) -> anyhow::Result<(u64, String)> {
+    // RSA key generation takes a while, keep it off the async workers
+    let (der, params) = tokio::task::spawn_blocking(move || generate_key_material(alg)).await??;
+
+    let kid = generate_kid();
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(KeyAlgorithm::from_str(alg_name(alg))?),
+            key_id: Some(kid.clone()),
+            ..CommonParameters::default()
+        },
+        algorithm: params,
+    };
+
+    let private_key = match app.signing_key_encryption() {
+        Some(encryption) => seal_private_key(encryption, &kid, &der)?,
+        None => der,
+    };
+    let id = create_signing_key(
+        app.pool(),
+        &kid,
+        alg_name(alg),
+        &private_key,
+        app.signing_key_encryption().is_some(),
+        serde_json::to_string(&jwk)?.as_str(),
+        status,
+    )
+    .await?;
+
+    Ok((id, kid))
+}
+
+pub async fn reload_signing_keys(app: &AppState) -> anyhow::Result<()> {
+    // read the generation first, a change made while loading then triggers another reload
+    let generation = get_signing_keys_generation(app).await?;
+    let rows = get_published_signing_keys(app.pool(), RETIRED_KEY_GRACE_SECS).await?;
+
+    let keys = rows
+        .iter()
+        .filter(|row| row.status == "active")
+        .map(|row| SigningKey::from_row(row, app.signing_key_encryption()).map(Arc::new))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let published = rows
+        .iter()
+        .map(|row| serde_json::from_str(&row.public_jwk))
+        .collect::<Result<Vec<Jwk>, _>>()?;
+
+    let mut store = app
+        .signing_keys()
+        .write()
+        .map_err(|_| anyhow::anyhow!("Signing key store lock poisoned"))?;
+    store.generation = generation;
+    store.keys = keys;
+    store.published = published;
+    Ok(())
+}
+
+// Called on startup: makes sure there is a key for the configured algorithm
+pub async fn init_signing_keys(app: &AppState) -> anyhow::Result<()> {
+    if app.signing_key_encryption().is_none() {
+        warn!("SIGNING_KEY_ENCRYPTION_KEY is not set, new signing keys are stored unencrypted");
+    }
+    reload_signing_keys(app).await?;
+
+    if current_signing_key(app).is_err() {
+        // activated right away, which also retires the keys of a previous TOKEN_SIGNING_ALG
+        let (id, kid) = create_key(app, app.signing_alg(), "pending").await?;
+        activate_signing_key(app.pool(), id).await?;
+        info!(
+            "Generated {} signing key {kid}",
+            alg_name(app.signing_alg())
+        );
+        bump_signing_keys_generation(app).await?;
+        reload_signing_keys(app).await?;
+    }
+
+    Ok(())
+}
+
+enum RotationStep<'a> {
+    Wait,
+    Schedule,
+    Activate(&'a SigningKeySchedule),
+}
+
+// Only keys of `alg` count: after a TOKEN_SIGNING_ALG change the old algorithm's active key is
+// no reason to wait, it is retired once a key for the new one is activated
+fn rotation_step<'a>(
+    schedule: &'a [SigningKeySchedule],
+    alg: &str,
+    rotation_secs: u64,
+    force: bool,
+) -> RotationStep<'a> {
+    let pending = schedule
+        .iter()
+        .find(|k| k.alg == alg && k.status == "pending");
+    let active = schedule
+        .iter()
+        .find(|k| k.alg == alg && k.status == "active");
+
+    match (pending, active) {
+        (Some(pending), _) if pending.age_secs >= KEY_PUBLISH_DELAY_SECS => {
+            RotationStep::Activate(pending)
+        }
+        (Some(_), _) => RotationStep::Wait,
+        (None, Some(active)) if !force && active.age_secs < rotation_secs => RotationStep::Wait,
+        (None, _) => RotationStep::Schedule,
+    }
+}
+
+/*
+ * One step of the pending -> active -> retired schedule for the configured algorithm:
+ * - no pending key and the active one is older than the rotation period (or `force`): create a pending key
+ * - pending key published for KEY_PUBLISH_DELAY_SECS: activate it, retiring every other key
+ * - retired keys whose tokens have all expired are deleted
+ */
+pub async fn rotate_signing_keys(app: &AppState, force: bool) -> anyhow::Result<KeyRotation> {
+    if !acquire_key_rotation_lock(app, KEY_ROTATION_CHECK_SECS / 2).await? {
+        return Ok(KeyRotation::Busy);
+    }
+
+    let alg = app.signing_alg();
+    let schedule = get_signing_key_schedule(app.pool()).await?;
+    let outcome = match rotation_step(&schedule, alg_name(alg), app.key_rotation_secs(), force) {
+        RotationStep::Activate(pending) => {
+            if activate_signing_key(app.pool(), pending.id).await? {
+                KeyRotation::Activated(pending.kid.clone())
+            } else {
+                KeyRotation::Unchanged
+            }
+        }
+        RotationStep::Wait => KeyRotation::Unchanged,
+        RotationStep::Schedule => KeyRotation::Scheduled(create_key(app, alg, "pending").await?.1),
+    };
+
+    let deleted = delete_retired_signing_keys(app.pool(), RETIRED_KEY_GRACE_SECS).await?;
+    if deleted > 0 || !matches!(outcome, KeyRotation::Unchanged) {
+        bump_signing_keys_generation(app).await?;
+    }
+
+    Ok(outcome)
+}
+
+// Reloads the key store if another instance (or this one) changed the keys
+pub async fn sync_signing_keys(app: &AppState) -> anyhow::Result<()> {
+    let loaded = app
+        .signing_keys()
+        .read()
+        .map_err(|_| anyhow::anyhow!("Signing key store lock poisoned"))?
+        .generation;
+
+    if get_signing_keys_generation(app).await? != loaded {
+        reload_signing_keys(app).await?;
+    }
+    Ok(())
+}
+
+// Background task run by every instance, the redis lock keeps the steps from overlapping
+pub fn spawn_key_rotation(app: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(KEY_ROTATION_CHECK_SECS));
+        loop {
+            interval.tick().await;
+
+            match rotate_signing_keys(&app, false).await {
+                Ok(KeyRotation::Scheduled(kid)) => info!("Published pending signing key {kid}"),
+                Ok(KeyRotation::Activated(kid)) => info!("Activated signing key {kid}"),
+                Ok(_) => {}
+                Err(e) => error!("Signing key rotation failed: {e}"),
+            }
+
+            if let Err(e) = sync_signing_keys(&app).await {
+                error!("Reloading signing keys failed: {e}");
+            }
+        }
+    });
+}
+
+pub fn current_signing_key(app: &AppState) -> anyhow::Result<Arc<SigningKey>> {
+    app.signing_keys()
+        .read()
+        .map_err(|_| anyhow::anyhow!("Signing key store lock poisoned"))?
+        .signing_key(app.signing_alg())
+        .ok_or_else(|| anyhow::anyhow!("No active signing key"))
+}
+
+pub fn published_jwks(app: &AppState) -> anyhow::Result<JwkSet> {
+    Ok(app
+        .signing_keys()
+        .read()
+        .map_err(|_| anyhow::anyhow!("Signing key store lock poisoned"))?
+        .jwks())
+}
+
+// Public key for a `kid` from a token header, None if it is not (or no longer) published
+pub fn verification_key(app: &AppState, kid: &str) -> anyhow::Result<Option<Jwk>> {
+    Ok(app
+        .signing_keys()
+        .read()
+        .map_err(|_| anyhow::anyhow!("Signing key store lock poisoned"))?
+        .verification_key(kid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(kid: &str, private_key: Vec<u8>) -> SigningKeyRow {
+        SigningKeyRow {
+            kid: kid.to_string(),
+            alg: "EdDSA".to_string(),
+            private_key,
+            private_key_encrypted: true,
+            public_jwk: String::new(),
+            status: "active".to_string(),
+        }
+    }
+
+    fn scheduled(id: u64, alg: &str, status: &str, age_secs: u64) -> SigningKeySchedule {
+        SigningKeySchedule {
+            id,
+            kid: format!("kid-{id}"),
+            alg: alg.to_string(),
+            status: status.to_string(),
+            age_secs,
+        }
+    }
+
+    #[test]
+    fn rotation_waits_for_a_young_active_key() {
+        let schedule = [scheduled(1, "RS256", "active", 60)];
+        assert!(matches!(
+            rotation_step(&schedule, "RS256", 3600, false),
+            RotationStep::Wait
+        ));
+        assert!(matches!(
+            rotation_step(&schedule, "RS256", 3600, true),
+            RotationStep::Schedule
+        ));
+    }
+
+    #[test]
+    fn rotation_replaces_the_key_of_a_previous_alg() {
+        // TOKEN_SIGNING_ALG went from RS256 to ES256: a young RS256 key is no reason to wait
+        let schedule = [scheduled(1, "RS256", "active", 60)];
+        assert!(matches!(
+            rotation_step(&schedule, "ES256", 3600, false),
+            RotationStep::Schedule
+        ));
+
+        // the ES256 key gets activated once published long enough, retiring the RS256 one
+        let schedule = [
+            scheduled(2, "ES256", "pending", KEY_PUBLISH_DELAY_SECS),
+            scheduled(1, "RS256", "active", 60),
+        ];
+        assert!(matches!(
+            rotation_step(&schedule, "ES256", 3600, false),
+            RotationStep::Activate(key) if key.id == 2
+        ));
+    }
+
+    #[test]
+    fn sealed_private_key_opens_only_for_its_kid() {
+        let encryption = signing_key_encryption(&STANDARD.encode([7u8; 32])).unwrap();
+        let sealed = seal_private_key(&encryption, "kid-1", b"der").unwrap();
+        assert_ne!(&sealed[NONCE_LEN..NONCE_LEN + 3], b"der");
+
+        assert_eq!(
+            open_private_key(&row("kid-1", sealed.clone()), Some(&encryption)).unwrap(),
+            b"der"
+        );
+        assert!(open_private_key(&row("kid-2", sealed.clone()), Some(&encryption)).is_err());
+        assert!(open_private_key(&row("kid-1", sealed), None).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::repositories::clients::LifetimeOverrides;
+
+// Upper bound for any access token, retired signing keys stay published at least this long
+pub static MAX_ACCESS_TOKEN_LIFETIME_SECS: u64 = 24 * 60 * 60; // 1 day
+
+// How long issued credentials stay valid, in seconds
+#[derive(Debug, Clone, Copy)]
+pub struct Lifetimes {
+    pub access_token: u64,
+    // a refresh token expires when unused for this long, rotation starts the clock again
+    pub refresh_token_idle: u64,
+    // no rotation extends a token family past this, counted from the first token
+    pub refresh_token_absolute: u64,
+    pub auth_code: u64,
+    // how long a login is accepted at /authorize
+    pub session: u64,
+}
+
+impl Default for Lifetimes {
+    fn default() -> Self {
+        Lifetimes {
+            access_token: 60 * 60,                     // 1 hour
+            refresh_token_idle: 30 * 24 * 60 * 60,     // 30 days
+            refresh_token_absolute: 90 * 24 * 60 * 60, // 90 days
+            auth_code: 10 * 60,                        // 10 minutes
+            session: 60 * 60,                          // 1 hour
+        }
+    }
+}
+
+fn env_secs(name: &str, default: u64) -> anyhow::Result<u64> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+    match value.parse() {
+        Ok(secs) if secs > 0 => Ok(secs),
+        _ => Err(anyhow::anyhow!(
+            "{name} must be a positive number of seconds"
+        )),
+    }
+}
+
+impl Lifetimes {
+    // Server-wide defaults, each can be overridden per client (see `for_client`)
+    pub fn from_env() -> anyhow::Result<Self> {
+        let defaults = Lifetimes::default();
+        let lifetimes = Lifetimes {
+            access_token: env_secs("ACCESS_TOKEN_LIFETIME_SECS", defaults.access_token)?,
+            refresh_token_idle: env_secs(
+                "REFRESH_TOKEN_IDLE_LIFETIME_SECS",
+                defaults.refresh_token_idle,
+            )?,
+            refresh_token_absolute: env_secs(
+                "REFRESH_TOKEN_ABSOLUTE_LIFETIME_SECS",
+                defaults.refresh_token_absolute,
+            )?,
+            auth_code: env_secs("AUTH_CODE_LIFETIME_SECS", defaults.auth_code)?,
+            session: env_secs("SESSION_LIFETIME_SECS", defaults.session)?,
+        };
+        if lifetimes.access_token > MAX_ACCESS_TOKEN_LIFETIME_SECS {
+            return Err(anyhow::anyhow!(
+                "ACCESS_TOKEN_LIFETIME_SECS must not exceed {MAX_ACCESS_TOKEN_LIFETIME_SECS}"
+            ));
+        }
+        Ok(lifetimes)
+    }
+
+    // What applies to a client: its own values where it has them, the server's otherwise
+    pub fn for_client(&self, overrides: &LifetimeOverrides) -> Lifetimes {
+        Lifetimes {
+            access_token: overrides.access_token.unwrap_or(self.access_token),
+            refresh_token_idle: overrides
+                .refresh_token_idle
+                .unwrap_or(self.refresh_token_idle),
+            refresh_token_absolute: overrides
+                .refresh_token_absolute
+                .unwrap_or(self.refresh_token_absolute),
+            auth_code: overrides.auth_code.unwrap_or(self.auth_code),
+            // the session itself only lives as long as the server allows, a client can only shorten it
+            session: overrides
+                .session
+                .map_or(self.session, |secs| secs.min(self.session)),
+        }
+    }
+}
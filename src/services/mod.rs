@@ -1,11 +1,25 @@
+pub mod authorization_details;
 pub mod authorize;
 pub mod cache;
 pub mod client;
+pub mod client_assertion;
+pub mod device;
+pub mod dpop;
+pub mod introspect;
+pub mod jarm;
+pub mod keys;
+pub mod lifetimes;
+pub mod mtls;
 pub mod password;
+pub mod pkce;
+pub mod refresh_token;
+pub mod request_object;
+pub mod resource;
+pub mod revoke;
 pub mod token;
 pub mod user;
 
 pub use authorize::authorize as authorize_svc;
 pub use authorize::AuthorizeInput;
-pub use token::issue_jwt;
+pub use token::issue_access_token;
 pub use token::TokenInput;
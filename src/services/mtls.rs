@@ -0,0 +1,215 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use rustls::pki_types::{CertificateDer, UnixTime};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::objects::{oid2abbrev, oid_registry};
+use x509_parser::parse_x509_certificate;
+
+use crate::repositories::clients::{ClientSettings, TokenEndpointAuthMethod};
+use crate::services::client_assertion::client_jwks;
+use crate::state::AppState;
+
+// The certificate chain a client presented in the TLS handshake, see `tls::serve_tls`
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    chain: Arc<Vec<CertificateDer<'static>>>,
+}
+
+impl ClientCertificate {
+    pub fn new(chain: &[CertificateDer<'_>]) -> Option<Self> {
+        if chain.is_empty() {
+            return None;
+        }
+        Some(ClientCertificate {
+            chain: Arc::new(chain.iter().map(|c| c.clone().into_owned()).collect()),
+        })
+    }
+
+    fn end_entity(&self) -> &CertificateDer<'static> {
+        &self.chain[0]
+    }
+
+    // RFC 8705 section 3.1: base64url SHA-256 of the DER certificate, used as cnf.x5t#S256
+    pub fn thumbprint(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.end_entity()))
+    }
+
+    fn parse(&self) -> anyhow::Result<X509Certificate<'_>> {
+        let (_, cert) = parse_x509_certificate(self.end_entity())
+            .map_err(|e| anyhow::anyhow!("Invalid client certificate: {e}"))?;
+        Ok(cert)
+    }
+}
+
+// RFC 4514 section 2.4: special characters anywhere, `#` or space up front and a trailing space
+fn escape_dn_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if i == 0 || i == last => escaped.push('\\'),
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// RFC 4514 string form (most specific RDN first, no spaces), e.g. "CN=payments,O=Example\, Inc.,C=DE"
+fn subject_dn(cert: &X509Certificate<'_>) -> String {
+    let mut rdns: Vec<String> = cert
+        .subject()
+        .iter()
+        .map(|rdn| {
+            rdn.iter()
+                .map(|attr| {
+                    let name = oid2abbrev(attr.attr_type(), oid_registry())
+                        .map_or_else(|_| attr.attr_type().to_id_string(), str::to_string);
+                    format!(
+                        "{name}={}",
+                        escape_dn_value(attr.as_str().unwrap_or_default())
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("+")
+        })
+        .collect();
+    rdns.reverse();
+    rdns.join(",")
+}
+
+// RFC 8705 section 2.1: issued by a CA we trust (TLS_CLIENT_CA_PATH) to the registered subject
+fn verify_tls_client_auth(
+    app: &AppState,
+    settings: &ClientSettings,
+    certificate: &ClientCertificate,
+) -> anyhow::Result<()> {
+    let verifier = app
+        .client_ca_verifier()
+        .ok_or_else(|| anyhow::anyhow!("tls_client_auth is not enabled on this server"))?;
+    verifier
+        .verify_client_cert(
+            certificate.end_entity(),
+            &certificate.chain[1..],
+            UnixTime::now(),
+        )
+        .map_err(|e| anyhow::anyhow!("Client certificate is not trusted: {e}"))?;
+
+    let expected = settings
+        .tls_client_auth_subject_dn
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("Client has no registered certificate subject"))?;
+    if subject_dn(&certificate.parse()?) != expected {
+        return Err(anyhow::anyhow!(
+            "Client certificate subject does not match the registration"
+        ));
+    }
+    Ok(())
+}
+
+// RFC 8705 section 2.2: the exact certificate must be registered (x5c in the client's JWKS)
+async fn verify_self_signed_tls_client_auth(
+    app: &AppState,
+    settings: &ClientSettings,
+    certificate: &ClientCertificate,
+) -> anyhow::Result<()> {
+    if !certificate.parse()?.validity().is_valid() {
+        return Err(anyhow::anyhow!(
+            "Client certificate is expired or not yet valid"
+        ));
+    }
+
+    let jwks = client_jwks(app, settings).await?;
+    let registered = jwks
+        .keys
+        .iter()
+        .filter_map(|jwk| jwk.common.x509_chain.as_ref()?.first())
+        .any(|x5c| {
+            STANDARD
+                .decode(x5c)
+                .is_ok_and(|der| der == certificate.end_entity().as_ref())
+        });
+    if !registered {
+        return Err(anyhow::anyhow!("Client certificate is not registered"));
+    }
+    Ok(())
+}
+
+pub async fn verify_client_certificate(
+    app: &AppState,
+    settings: &ClientSettings,
+    certificate: &ClientCertificate,
+) -> anyhow::Result<()> {
+    match settings.token_endpoint_auth_method {
+        TokenEndpointAuthMethod::TlsClientAuth => {
+            verify_tls_client_auth(app, settings, certificate)
+        }
+        TokenEndpointAuthMethod::SelfSignedTlsClientAuth => {
+            verify_self_signed_tls_client_auth(app, settings, certificate).await
+        }
+        method => Err(anyhow::anyhow!(
+            "Client must authenticate with {}",
+            method.as_str()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+
+    // openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 36500
+    //   -subj "/C=DE/O=Example\, Inc./OU=#payments/CN=pay\+ments <eu>"
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----
+MIIB+zCCAaGgAwIBAgIUYxSUEoT9m5TQyCSP4JpV8+spNFMwCgYIKoZIzj0EAwIw
+UjELMAkGA1UEBhMCREUxFjAUBgNVBAoMDUV4YW1wbGUsIEluYy4xEjAQBgNVBAsM
+CSNwYXltZW50czEXMBUGA1UEAwwOcGF5K21lbnRzIDxldT4wIBcNMjYxMDE4MTIz
+NTI0WhgPMjEyNjA5MjQxMjM1MjRaMFIxCzAJBgNVBAYTAkRFMRYwFAYDVQQKDA1F
+eGFtcGxlLCBJbmMuMRIwEAYDVQQLDAkjcGF5bWVudHMxFzAVBgNVBAMMDnBheStt
+ZW50cyA8ZXU+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAELxilwUTeIeAwnQ8F
++f3g7bTG8yi+Z15vgv2h4a2jN8MTaWYYqQupTAPwALdOANdjxPJ47NHUq1S+1jXU
+gnCA2KNTMFEwHQYDVR0OBBYEFNqoY83DPdKyU2S/zlzdPK8NNZ2gMB8GA1UdIwQY
+MBaAFNqoY83DPdKyU2S/zlzdPK8NNZ2gMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZI
+zj0EAwIDSAAwRQIgV0rQLXY/AMCUtGkIQVi/BpIZbN3MQrpTLSydZJgiLJYCIQCy
+mXKU1khob4JVuRyxS2mHFbyxnGbO+Pztn4+FENuDdQ==
+-----END CERTIFICATE-----
+";
+
+    fn certificate() -> ClientCertificate {
+        let der = CertificateDer::from_pem_slice(CERTIFICATE.as_bytes()).unwrap();
+        ClientCertificate::new(&[der]).unwrap()
+    }
+
+    #[test]
+    fn subject_dn_is_rfc4514_ordered_and_escaped() {
+        let certificate = certificate();
+        assert_eq!(
+            subject_dn(&certificate.parse().unwrap()),
+            r"CN=pay\+ments \<eu\>,OU=\#payments,O=Example\, Inc.,C=DE"
+        );
+    }
+
+    #[test]
+    fn thumbprint_is_base64url_sha256_of_der() {
+        // openssl x509 -outform der | openssl dgst -sha256 -binary | base64url
+        assert_eq!(
+            certificate().thumbprint(),
+            "VwBJmVIVTb4Cbd796LIdLJb-hna971axgAeIhFZshdw"
+        );
+    }
+
+    #[test]
+    fn dn_values_escape_leading_and_trailing_spaces() {
+        assert_eq!(escape_dn_value(" a b "), r"\ a b\ ");
+        assert_eq!(escape_dn_value("a#b"), "a#b");
+    }
+}
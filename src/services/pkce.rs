@@ -0,0 +1,101 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+// RFC 7636 section 4.1: 43-128 chars from the unreserved set
+fn is_valid_pkce_value(value: &str) -> bool {
+    (43..=128).contains(&value.len())
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'))
+}
+
+// Normalizes the requested method; a challenge without a method means "plain" per the RFC
+pub fn validate_challenge(challenge: &str, method: Option<&str>) -> anyhow::Result<String> {
+    let method = match method.unwrap_or("plain") {
+        m @ ("S256" | "plain") => m.to_string(),
+        other => {
+            return Err(anyhow::anyhow!(
+                "Unsupported code_challenge_method: {other}"
+            ))
+        }
+    };
+
+    if !is_valid_pkce_value(challenge) {
+        return Err(anyhow::anyhow!("Malformed code_challenge"));
+    }
+
+    Ok(method)
+}
+
+fn verify_code_verifier(verifier: &str, challenge: &str, method: &str) -> bool {
+    if !is_valid_pkce_value(verifier) {
+        return false;
+    }
+
+    match method {
+        "S256" => URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) == challenge,
+        "plain" => verifier == challenge,
+        _ => false,
+    }
+}
+
+// Checks the code_verifier sent to /token against what was stored with the auth code
+pub fn check_code_verifier(
+    challenge: Option<&str>,
+    method: Option<&str>,
+    verifier: Option<&str>,
+) -> Result<(), &'static str> {
+    match (challenge, verifier) {
+        (Some(challenge), Some(verifier)) => {
+            if verify_code_verifier(verifier, challenge, method.unwrap_or("plain")) {
+                Ok(())
+            } else {
+                Err("code_verifier does not match code_challenge")
+            }
+        }
+        (Some(_), None) => Err("code_verifier is required"),
+        (None, Some(_)) => Err("code_verifier sent but no code_challenge was used"),
+        (None, None) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 7636 Appendix B
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn s256_accepts_the_rfc_example() {
+        assert_eq!(
+            check_code_verifier(Some(CHALLENGE), Some("S256"), Some(VERIFIER)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn plain_compares_verbatim() {
+        assert_eq!(
+            check_code_verifier(Some(VERIFIER), None, Some(VERIFIER)),
+            Ok(())
+        );
+        assert!(check_code_verifier(Some(CHALLENGE), Some("plain"), Some(VERIFIER)).is_err());
+    }
+
+    #[test]
+    fn rejects_a_mismatched_or_missing_verifier() {
+        let other = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXl";
+        assert_eq!(
+            check_code_verifier(Some(CHALLENGE), Some("S256"), Some(other)),
+            Err("code_verifier does not match code_challenge")
+        );
+        assert_eq!(
+            check_code_verifier(Some(CHALLENGE), Some("S256"), None),
+            Err("code_verifier is required")
+        );
+        assert!(check_code_verifier(None, None, Some(VERIFIER)).is_err());
+    }
+}
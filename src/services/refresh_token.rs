@@ -0,0 +1,345 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::repositories::clients::Client;
+use crate::repositories::refresh_tokens::{
+    create_refresh_token, get_by_token_hash, get_derived_access_tokens, replace_refresh_token,
+    revoke_family, set_access_token_jti, NewRefreshToken, RefreshToken,
+};
+use crate::repositories::resources::Resource;
+use crate::services::cache::deny_access_token;
+use crate::services::resource::resource_scopes;
+use crate::services::token::{grant_scopes, AccessToken, AccessTokenGrant};
+use crate::state::AppState;
+
+struct IssuedRefreshToken {
+    id: u64,
+    refresh_token: String,
+}
+
+pub struct RotatedRefreshToken {
+    pub id: u64,
+    pub refresh_token: String,
+    pub user_id: String,
+    // scope for the new access token, the refresh token keeps the original one
+    pub scope: String,
+    pub authorization_details: Option<serde_json::Value>,
+}
+
+pub enum RefreshOutcome {
+    Rotated(RotatedRefreshToken),
+    Rejected(&'static str),
+    // requested more than the original grant
+    InvalidScope,
+}
+
+fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32]; // 256-bit
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Refresh tokens are high-entropy random strings, so a plain sha256 is enough
+// and keeps lookups by hash possible (unlike argon2 with a per-row salt).
+pub fn hash_refresh_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+// Each token is valid for the client's idle lifetime, but never past the family's absolute one
+async fn store_refresh_token(
+    app: &AppState,
+    client: &Client,
+    new_token: &NewRefreshToken<'_>,
+) -> anyhow::Result<IssuedRefreshToken> {
+    let token = generate_refresh_token();
+    let id = create_refresh_token(
+        app.pool(),
+        hash_refresh_token(&token).as_str(),
+        new_token,
+        app.lifetimes()
+            .for_client(&client.settings.lifetimes)
+            .refresh_token_idle,
+    )
+    .await?;
+    Ok(IssuedRefreshToken {
+        id,
+        refresh_token: token,
+    })
+}
+
+// Starts a new token family, used when a grant (e.g. an auth code) is first exchanged.
+// `access_token` is the one issued in the same response for `grant`, `dpop_jkt` binds the family
+// to the DPoP key of the request.
+pub async fn issue_refresh_token(
+    app: &AppState,
+    client: &Client,
+    grant: &AccessTokenGrant<'_>,
+    access_token: &AccessToken,
+    dpop_jkt: Option<&str>,
+) -> anyhow::Result<String> {
+    let family_id = uuid::Uuid::new_v4().to_string();
+    let authorization_details = grant.authorization_details.map(ToString::to_string);
+    let issued = store_refresh_token(
+        app,
+        client,
+        &NewRefreshToken {
+            family_id: &family_id,
+            client_id_ref: client.id,
+            user_id: grant.user_id,
+            scope: grant.scope,
+            authorization_details: authorization_details.as_deref(),
+            dpop_jkt,
+            family_ttl_secs: app
+                .lifetimes()
+                .for_client(&client.settings.lifetimes)
+                .refresh_token_absolute,
+        },
+    )
+    .await?;
+    link_access_token(app, issued.id, access_token).await?;
+    Ok(issued.refresh_token)
+}
+
+// Records the access token issued together with a refresh token, see `revoke_refresh_family`
+pub async fn link_access_token(
+    app: &AppState,
+    refresh_token_id: u64,
+    access_token: &AccessToken,
+) -> anyhow::Result<()> {
+    set_access_token_jti(
+        app.pool(),
+        refresh_token_id,
+        &access_token.jti,
+        access_token.expires_in,
+    )
+    .await?;
+    Ok(())
+}
+
+// Revokes every refresh token of the family and deny-lists the access tokens derived from them
+pub async fn revoke_refresh_family(app: &AppState, family_id: &str) -> anyhow::Result<()> {
+    revoke_family(app.pool(), family_id).await?;
+
+    for derived in get_derived_access_tokens(app.pool(), family_id).await? {
+        if derived.remaining_secs > 0 {
+            deny_access_token(app, &derived.jti, derived.remaining_secs).await?;
+        }
+    }
+    Ok(())
+}
+
+// Why a presented refresh token is not rotated
+#[derive(Debug, PartialEq, Eq)]
+enum Refusal {
+    Rejected(&'static str),
+    // already rotated: the family has leaked
+    Reused,
+}
+
+// Only a token the calling client still holds legitimately counts as reuse, the others are just refused
+fn check_presented(
+    current: &RefreshToken,
+    client_id_ref: u64,
+    dpop_jkt: Option<&str>,
+) -> Result<(), Refusal> {
+    if current.client_id_ref != client_id_ref {
+        return Err(Refusal::Rejected(
+            "refresh token was not issued to this client",
+        ));
+    }
+
+    if current.revoked {
+        return Err(Refusal::Rejected("refresh token has been revoked"));
+    }
+
+    if current.expired {
+        return Err(Refusal::Rejected("refresh token has expired"));
+    }
+
+    if current.rotated {
+        return Err(Refusal::Reused);
+    }
+
+    if current.dpop_jkt.is_some() && current.dpop_jkt.as_deref() != dpop_jkt {
+        return Err(Refusal::Rejected(
+            "refresh token is bound to a different DPoP key",
+        ));
+    }
+    Ok(())
+}
+
+async fn reuse_detected(app: &AppState, family_id: &str) -> anyhow::Result<RefreshOutcome> {
+    warn!("Refresh token reuse detected, revoking family {family_id}");
+    revoke_refresh_family(app, family_id).await?;
+    Ok(RefreshOutcome::Rejected(
+        "refresh token has already been used",
+    ))
+}
+
+/*
+ * Exchanges a refresh token for a new one in the same family.
+ * Presenting a token that was already rotated means it leaked (or was replayed),
+ * so the whole family is revoked and the legitimate holder has to log in again.
+ * `requested_scope` may narrow the scope of the new access token (RFC 6749 section 6),
+ * so does `resource` (RFC 8707), the new access token only gets the scopes that resource knows.
+ * A family bound to a DPoP key can only be refreshed with a proof for that key (`dpop_jkt`).
+ */
+pub async fn rotate_refresh_token(
+    app: &AppState,
+    client: &Client,
+    presented: &str,
+    requested_scope: Option<&str>,
+    resource: Option<&Resource>,
+    dpop_jkt: Option<&str>,
+) -> anyhow::Result<RefreshOutcome> {
+    let Some(current) = get_by_token_hash(app.pool(), &hash_refresh_token(presented)).await? else {
+        return Ok(RefreshOutcome::Rejected("refresh token not found"));
+    };
+
+    match check_presented(&current, client.id, dpop_jkt) {
+        Ok(()) => {}
+        Err(Refusal::Rejected(reason)) => return Ok(RefreshOutcome::Rejected(reason)),
+        Err(Refusal::Reused) => return reuse_detected(app, &current.family_id).await,
+    }
+
+    let granted: Vec<String> = current
+        .scope
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    let Some(mut scope) = grant_scopes(requested_scope, &granted) else {
+        return Ok(RefreshOutcome::InvalidScope);
+    };
+    if let Some(resource) = resource {
+        let Some(narrowed) = resource_scopes(&scope, resource) else {
+            return Ok(RefreshOutcome::InvalidScope);
+        };
+        scope = narrowed;
+    }
+
+    let refresh_token = generate_refresh_token();
+    // a concurrent request may have rotated it between the read and the update
+    let Some(id) = replace_refresh_token(
+        app.pool(),
+        current.id,
+        &hash_refresh_token(&refresh_token),
+        &NewRefreshToken {
+            family_id: &current.family_id,
+            client_id_ref: current.client_id_ref,
+            user_id: &current.user_id,
+            scope: &current.scope,
+            authorization_details: current.authorization_details.as_deref(),
+            dpop_jkt: current.dpop_jkt.as_deref(),
+            family_ttl_secs: current.family_remaining_secs,
+        },
+        app.lifetimes()
+            .for_client(&client.settings.lifetimes)
+            .refresh_token_idle,
+    )
+    .await?
+    else {
+        return reuse_detected(app, &current.family_id).await;
+    };
+
+    Ok(RefreshOutcome::Rotated(RotatedRefreshToken {
+        id,
+        refresh_token,
+        user_id: current.user_id,
+        scope,
+        authorization_details: current
+            .authorization_details
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: u64 = 7;
+
+    fn token() -> RefreshToken {
+        RefreshToken {
+            id: 1,
+            family_id: "family".to_string(),
+            client_id_ref: CLIENT,
+            client_id: "client".to_string(),
+            user_id: "user".to_string(),
+            scope: "read".to_string(),
+            authorization_details: None,
+            dpop_jkt: None,
+            issued_at: 0,
+            expires_at: 0,
+            family_remaining_secs: 3600,
+            expired: false,
+            rotated: false,
+            revoked: false,
+        }
+    }
+
+    #[test]
+    fn a_current_token_is_rotated() {
+        assert_eq!(check_presented(&token(), CLIENT, None), Ok(()));
+    }
+
+    #[test]
+    fn a_rotated_token_is_reuse() {
+        let rotated = RefreshToken {
+            rotated: true,
+            ..token()
+        };
+        assert_eq!(
+            check_presented(&rotated, CLIENT, None),
+            Err(Refusal::Reused)
+        );
+    }
+
+    // once the family is revoked, further replays must not count as reuse again
+    #[test]
+    fn a_revoked_token_is_only_refused() {
+        let revoked = RefreshToken {
+            rotated: true,
+            revoked: true,
+            ..token()
+        };
+        assert_eq!(
+            check_presented(&revoked, CLIENT, None),
+            Err(Refusal::Rejected("refresh token has been revoked"))
+        );
+    }
+
+    // another client must not be able to revoke the family by presenting a leaked token
+    #[test]
+    fn another_clients_token_is_not_reuse() {
+        let rotated = RefreshToken {
+            rotated: true,
+            ..token()
+        };
+        assert_eq!(
+            check_presented(&rotated, CLIENT + 1, None),
+            Err(Refusal::Rejected(
+                "refresh token was not issued to this client"
+            ))
+        );
+    }
+
+    #[test]
+    fn a_bound_token_needs_its_dpop_key() {
+        let bound = RefreshToken {
+            dpop_jkt: Some("jkt".to_string()),
+            ..token()
+        };
+        assert_eq!(check_presented(&bound, CLIENT, Some("jkt")), Ok(()));
+        assert_eq!(
+            check_presented(&bound, CLIENT, None),
+            Err(Refusal::Rejected(
+                "refresh token is bound to a different DPoP key"
+            ))
+        );
+    }
+}
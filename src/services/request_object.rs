@@ -0,0 +1,61 @@
+use jsonwebtoken::{decode, decode_header, Validation};
+use serde::Deserialize;
+
+use crate::repositories::clients::get_by_client_token;
+use crate::services::client_assertion::client_key;
+use crate::services::TokenInput;
+use crate::state::AppState;
+
+// The authorization request parameters a request object may carry (RFC 9101 section 4)
+#[derive(Deserialize)]
+pub struct RequestObject {
+    pub client_id: Option<String>,
+    pub response_type: Option<String>,
+    pub response_mode: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub resource: Option<String>,
+    // a JSON array here, not a string
+    pub authorization_details: Option<serde_json::Value>,
+}
+
+/*
+ * RFC 9101 section 6: a JWS signed with one of the client's registered keys,
+ * iss is the client_id, aud is Loom's issuer and exp is required.
+ */
+pub async fn verify_request_object(
+    app: &AppState,
+    client_id: &str,
+    request: &str,
+) -> anyhow::Result<RequestObject> {
+    let Some(client) = get_by_client_token(
+        app.pool(),
+        &TokenInput {
+            client_id: client_id.to_string(),
+            redirect_uri: None,
+        },
+    )
+    .await?
+    else {
+        return Err(anyhow::anyhow!("Client not found"));
+    };
+
+    let header = decode_header(request)?;
+    let key = client_key(app, &client.settings, &header, "request object").await?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[app.issuer()]);
+    validation.set_issuer(&[client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+
+    let claims = decode::<RequestObject>(request, &key, &validation)?.claims;
+    if matches!(claims.client_id.as_deref(), Some(id) if id != client_id) {
+        return Err(anyhow::anyhow!(
+            "request object client_id does not match the client_id parameter"
+        ));
+    }
+    Ok(claims)
+}
@@ -0,0 +1,35 @@
+use axum::http::Uri;
+
+use crate::repositories::resources::{create_resource, get_by_identifier, Resource};
+use crate::state::AppState;
+
+// RFC 8707 section 2: an absolute URI without a fragment
+pub fn is_valid_resource_indicator(identifier: &str) -> bool {
+    !identifier.contains('#')
+        && identifier
+            .parse::<Uri>()
+            .is_ok_and(|uri| uri.scheme().is_some() && uri.host().is_some())
+}
+
+pub async fn register_resource_service(
+    app: &AppState,
+    identifier: &str,
+    name: &str,
+    scopes: &[String],
+) -> anyhow::Result<u64> {
+    let id = create_resource(app.pool(), identifier, name, scopes).await?;
+    Ok(id)
+}
+
+pub async fn find_resource(app: &AppState, identifier: &str) -> anyhow::Result<Option<Resource>> {
+    Ok(get_by_identifier(app.pool(), identifier).await?)
+}
+
+// The part of a (space-delimited) scope the resource understands, None if nothing is left
+pub fn resource_scopes(scope: &str, resource: &Resource) -> Option<String> {
+    let scopes: Vec<&str> = scope
+        .split_whitespace()
+        .filter(|s| resource.scopes.iter().any(|r| r == s))
+        .collect();
+    (!scopes.is_empty()).then(|| scopes.join(" "))
+}
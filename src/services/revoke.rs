@@ -0,0 +1,94 @@
+use tracing::info;
+
+use crate::repositories::refresh_tokens::get_by_token_hash;
+use crate::services::cache::deny_access_token;
+use crate::services::refresh_token::{hash_refresh_token, revoke_refresh_family};
+use crate::services::token::{is_opaque_token, revoke_opaque_token, verify_access_token};
+use crate::state::AppState;
+
+// Returns false if the token is not an access token issued to this client
+async fn revoke_access_token(app: &AppState, client_id: &str, token: &str) -> anyhow::Result<bool> {
+    let Ok(claims) = verify_access_token(app, token).await else {
+        return Ok(false);
+    };
+    if claims.client_id != client_id {
+        return Ok(false);
+    }
+
+    if is_opaque_token(token) {
+        revoke_opaque_token(app, token).await?;
+        return Ok(true);
+    }
+
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    if let Some(remaining) = deny_list_ttl(claims.exp, now) {
+        deny_access_token(app, &claims.jti, remaining).await?;
+    }
+    Ok(true)
+}
+
+// A revoked JWT stays on the deny-list until it would have expired, expired ones are skipped
+fn deny_list_ttl(exp: i64, now: i64) -> Option<u64> {
+    u64::try_from(exp - now)
+        .ok()
+        .filter(|remaining| *remaining > 0)
+}
+
+// Returns false if the token is not a refresh token issued to this client
+async fn revoke_refresh_token(
+    app: &AppState,
+    client_id_ref: u64,
+    token: &str,
+) -> anyhow::Result<bool> {
+    let Some(rt) = get_by_token_hash(app.pool(), &hash_refresh_token(token)).await? else {
+        return Ok(false);
+    };
+    if rt.client_id_ref != client_id_ref {
+        return Ok(false);
+    }
+
+    // the whole family goes, together with the access tokens it produced
+    revoke_refresh_family(app, &rt.family_id).await?;
+    info!("Revoked refresh token family {}", rt.family_id);
+    Ok(true)
+}
+
+/*
+ * RFC 7009: unknown tokens and tokens of other clients are ignored, the endpoint
+ * answers the same either way. The hint only decides which kind is tried first.
+ */
+pub async fn revoke(
+    app: &AppState,
+    client_id_ref: u64,
+    client_id: &str,
+    token: &str,
+    token_type_hint: Option<&str>,
+) -> anyhow::Result<()> {
+    if token_type_hint == Some("access_token") {
+        if !revoke_access_token(app, client_id, token).await? {
+            revoke_refresh_token(app, client_id_ref, token).await?;
+        }
+    } else if !revoke_refresh_token(app, client_id_ref, token).await? {
+        revoke_access_token(app, client_id, token).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn live_tokens_are_denied_until_they_expire() {
+        assert_eq!(deny_list_ttl(NOW + 300, NOW), Some(300));
+        assert_eq!(deny_list_ttl(NOW + 1, NOW), Some(1));
+    }
+
+    #[test]
+    fn expired_tokens_are_not_deny_listed() {
+        assert_eq!(deny_list_ttl(NOW, NOW), None);
+        assert_eq!(deny_list_ttl(NOW - 300, NOW), None);
+    }
+}
@@ -1,76 +1,319 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use core::result::Result::{Err, Ok};
-use jsonwebtoken::{encode, EncodingKey, Header};
-use serde::Serialize;
+use jsonwebtoken::{decode, decode_header, encode, DecodingKey, Header, Validation};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::str;
-use time::{Duration, OffsetDateTime};
+use std::time::Duration;
+use time::OffsetDateTime;
 
-use crate::repositories::clients::get_by_client_token;
+use crate::repositories::clients::{
+    get_by_client_token, AccessTokenFormat, Client, TokenEndpointAuthMethod,
+};
+use crate::services::cache::{
+    delete_opaque_token, get_opaque_token, is_access_token_denied, store_opaque_token,
+};
+use crate::services::client_assertion::verify_client_assertion;
+use crate::services::keys::{current_signing_key, parse_signing_alg, verification_key};
+use crate::services::mtls::{verify_client_certificate, ClientCertificate};
 use crate::services::password::verify_hash;
 use crate::state::AppState;
 
-#[derive(Debug, Serialize)]
+// RFC 9068 section 2.1, keeps access tokens from being mistaken for other JWTs (e.g. ID tokens)
+static ACCESS_TOKEN_TYP: &str = "at+jwt";
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    pub sub: String,   // subject (user id)
-    pub aud: String,   // audience (client id)
-    pub exp: i64,      // expiration (unix seconds)
-    pub iat: i64,      // issued-at
-    pub scope: String, // space-delimited scopes
+    pub iss: String,       // issuer, see `AppState::issuer`
+    pub sub: String,       // subject (user id)
+    pub aud: String,       // audience (resource identifier, or the client id without one)
+    pub exp: i64,          // expiration (unix seconds)
+    pub nbf: i64,          // not before
+    pub iat: i64,          // issued-at
+    pub jti: String,       // token id, used by the revocation deny-list
+    pub client_id: String, // client the token was issued to (RFC 9068 section 2.2)
+    pub scope: String,     // space-delimited scopes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization_details: Option<serde_json::Value>, // what the user approved (RFC 9396 section 9)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>, // proof-of-possession binding
+}
+
+// What an access token is issued for, see `issue_access_token`
+pub struct AccessTokenGrant<'a> {
+    pub user_id: &'a str,
+    // the resource the token is for, or the client itself
+    pub audience: &'a str,
+    pub scope: &'a str,
+    pub authorization_details: Option<&'a serde_json::Value>,
+}
+
+// Proof-of-possession: the token is only usable together with this client certificate
+// (RFC 8705 section 3.1) and/or a DPoP proof signed with this key (RFC 9449 section 6)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Confirmation {
+    #[serde(rename = "x5t#S256", skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jkt: Option<String>,
+}
+
+impl Confirmation {
+    pub fn new(x5t_s256: Option<String>, jkt: Option<String>) -> Option<Self> {
+        (x5t_s256.is_some() || jkt.is_some()).then_some(Confirmation { x5t_s256, jkt })
+    }
+
+    // RFC 9449 section 5: DPoP-bound tokens are not bearer tokens
+    pub fn token_type(cnf: Option<&Confirmation>) -> &'static str {
+        match cnf {
+            Some(Confirmation { jkt: Some(_), .. }) => "DPoP",
+            _ => "Bearer",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AccessToken {
+    pub token: String,
+    pub jti: String,
+    pub expires_in: u64, // seconds
 }
 
 #[derive(Debug)]
 pub struct TokenInput {
     pub client_id: String,
-    pub redirect_uri: String,
+    // only grants that carry a redirect_uri (authorization_code) constrain the lookup
+    pub redirect_uri: Option<String>,
 }
 
-async fn verify(
+// How the client proved its identity, see `routes::client_auth`
+pub enum ClientAuth {
+    Secret(String),
+    Assertion(String),
+    Certificate(ClientCertificate),
+}
+
+impl ClientAuth {
+    // Tokens issued to a client that authenticated with mTLS are bound to its certificate
+    pub fn certificate_thumbprint(&self) -> Option<String> {
+        match self {
+            ClientAuth::Certificate(certificate) => Some(certificate.thumbprint()),
+            _ => None,
+        }
+    }
+}
+
+pub async fn authenticate_client(
     app: &AppState,
     token_input: &TokenInput,
-    secret: &[u8],
-) -> Result<(), anyhow::Error> {
-    match get_by_client_token(app.pool(), token_input).await {
-        Ok(Some(client)) => {
-            if !verify_hash(secret, client.secret_hash.as_str()) {
+    auth: &ClientAuth,
+) -> Result<Client, anyhow::Error> {
+    let client = match get_by_client_token(app.pool(), token_input).await {
+        Ok(Some(client)) => client,
+        Ok(Option::None) => return Err(anyhow::anyhow!("Client not found")),
+        Err(e) => return Err(anyhow::anyhow!("Database error: {e}")),
+    };
+
+    // a client only gets to use the method it registered
+    let method = client.settings.token_endpoint_auth_method;
+    match auth {
+        ClientAuth::Assertion(assertion) if method == TokenEndpointAuthMethod::PrivateKeyJwt => {
+            verify_client_assertion(app, &token_input.client_id, &client.settings, assertion)
+                .await?;
+        }
+        ClientAuth::Certificate(certificate) if method.uses_certificate() => {
+            verify_client_certificate(app, &client.settings, certificate).await?;
+        }
+        ClientAuth::Secret(secret) if method.uses_secret() => {
+            if !verify_hash(secret.as_bytes(), client.secret_hash.as_str()) {
                 return Err(anyhow::anyhow!("Invalid client secret"));
             }
-            Ok(())
         }
-        Ok(Option::None) => Err(anyhow::anyhow!("Client not found")),
-        Err(e) => Err(anyhow::anyhow!("Database error: {e}")),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Client must authenticate with {}",
+                client.settings.token_endpoint_auth_method.as_str()
+            ));
+        }
     }
+
+    Ok(client)
 }
 
-pub async fn issue_jwt(
-    app: &AppState,
-    user_id: &str,
+// The JWT access token profile, RFC 9068 section 2.2
+fn access_token_claims(
+    issuer: &str,
     client_id: &str,
-    scope: &str,
-    secret: &[u8],
-    redirect_uri: &str,
-) -> anyhow::Result<String> {
-    let token_input = TokenInput {
-        client_id: client_id.to_string(),
-        redirect_uri: redirect_uri.to_string(),
-    };
-
-    // verifying secret
-    verify(app, &token_input, secret).await?;
-
+    grant: &AccessTokenGrant<'_>,
+    cnf: Option<Confirmation>,
+    ttl_secs: u64,
+) -> Claims {
     let now = OffsetDateTime::now_utc().unix_timestamp();
-    let exp = (OffsetDateTime::now_utc() + Duration::hours(1)).unix_timestamp();
+    let exp = (OffsetDateTime::now_utc() + Duration::from_secs(ttl_secs)).unix_timestamp();
 
-    let claims = Claims {
-        sub: user_id.to_string(),
-        aud: client_id.to_string(),
+    Claims {
+        iss: issuer.to_string(),
+        sub: grant.user_id.to_string(),
+        aud: grant.audience.to_string(),
         exp,
+        nbf: now,
         iat: now,
-        scope: scope.to_string(),
+        jti: uuid::Uuid::new_v4().to_string(),
+        client_id: client_id.to_string(),
+        scope: grant.scope.to_string(),
+        authorization_details: grant.authorization_details.cloned(),
+        cnf,
+    }
+}
+
+// The caller is expected to have authenticated the client with `authenticate_client`.
+// Issues the token in the format and for the lifetime the client registered,
+// see `issue_jwt` and `issue_opaque_token`.
+pub async fn issue_access_token(
+    app: &AppState,
+    client: &Client,
+    grant: &AccessTokenGrant<'_>,
+    cnf: Option<Confirmation>,
+) -> anyhow::Result<AccessToken> {
+    let ttl_secs = app
+        .lifetimes()
+        .for_client(&client.settings.lifetimes)
+        .access_token;
+    let claims = access_token_claims(app.issuer(), &client.name, grant, cnf, ttl_secs);
+
+    let token = match client.settings.access_token_format {
+        AccessTokenFormat::Jwt => issue_jwt(app, &claims)?,
+        AccessTokenFormat::Opaque => issue_opaque_token(app, &claims, ttl_secs).await?,
     };
+    Ok(AccessToken {
+        token,
+        jti: claims.jti,
+        expires_in: ttl_secs,
+    })
+}
+
+// Signed with the current server key, so anyone holding the published public key can verify it.
+fn issue_jwt(app: &AppState, claims: &Claims) -> anyhow::Result<String> {
+    let key = current_signing_key(app)?;
+
+    let header = Header {
+        typ: Some(ACCESS_TOKEN_TYP.to_string()),
+        kid: Some(key.kid.clone()),
+        ..Header::new(key.alg)
+    };
+
+    Ok(encode(&header, claims, key.encoding_key())?)
+}
+
+// Like refresh tokens, handles are random enough for a plain sha256, and redis never sees them
+fn hash_opaque_token(handle: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(handle.as_bytes()))
+}
+
+// JWTs have three dot-separated parts, opaque handles are plain base64url
+pub fn is_opaque_token(token: &str) -> bool {
+    !token.contains('.')
+}
+
+/*
+ * A random handle that means nothing outside Loom: the claims stay in redis until the
+ * token expires and can only be read through introspection (`verify_access_token`).
+ */
+async fn issue_opaque_token(
+    app: &AppState,
+    claims: &Claims,
+    ttl_secs: u64,
+) -> anyhow::Result<String> {
+    let mut bytes = [0u8; 32]; // 256-bit
+    OsRng.fill_bytes(&mut bytes);
+    let handle = URL_SAFE_NO_PAD.encode(bytes);
+
+    store_opaque_token(
+        app,
+        &hash_opaque_token(&handle),
+        &serde_json::to_string(claims)?,
+        ttl_secs,
+    )
+    .await?;
+    Ok(handle)
+}
 
-    let jwt = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret),
+pub async fn resolve_opaque_token(app: &AppState, handle: &str) -> anyhow::Result<Option<Claims>> {
+    match get_opaque_token(app, &hash_opaque_token(handle)).await? {
+        Some(serialized) => Ok(Some(serde_json::from_str(&serialized)?)),
+        None => Ok(None),
+    }
+}
+
+// Revoking an opaque token takes effect immediately, there is nothing left to resolve
+pub async fn revoke_opaque_token(app: &AppState, handle: &str) -> anyhow::Result<()> {
+    delete_opaque_token(app, &hash_opaque_token(handle)).await
+}
+
+// Checks the typ, the signature against the published keys, the issuer, nbf and the expiry.
+// The audience is the client the token was issued to, so checking it is up to the caller.
+pub fn verify_jwt(app: &AppState, token: &str) -> anyhow::Result<Claims> {
+    let header = decode_header(token)?;
+    if header.typ.as_deref() != Some(ACCESS_TOKEN_TYP) {
+        return Err(anyhow::anyhow!(
+            "Token is not an access token (typ {ACCESS_TOKEN_TYP})"
+        ));
+    }
+    let kid = header
+        .kid
+        .ok_or_else(|| anyhow::anyhow!("Token header has no kid"))?;
+    let jwk =
+        verification_key(app, &kid)?.ok_or_else(|| anyhow::anyhow!("Unknown signing key {kid}"))?;
+
+    // the algorithm comes from our key, never from the token header
+    let alg = parse_signing_alg(
+        &jwk.common
+            .key_algorithm
+            .ok_or_else(|| anyhow::anyhow!("Signing key {kid} has no alg"))?
+            .to_string(),
     )?;
-    Ok(jwt)
+    let mut validation = Validation::new(alg);
+    validation.validate_aud = false;
+    validation.validate_nbf = true;
+    validation.set_issuer(&[app.issuer()]);
+    validation.set_required_spec_claims(&["exp", "iss", "nbf"]);
+    validation.leeway = 0;
+
+    let data = decode::<Claims>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?;
+    Ok(data.claims)
+}
+
+// What /introspect and /revoke use: a valid signature is not enough once a token was revoked.
+// Loom serves no protected resources itself, so there is no bearer extractor built on this;
+// external resource servers get the same answer from /introspect.
+// Opaque tokens are resolved from redis, which drops them once they expire.
+pub async fn verify_access_token(app: &AppState, token: &str) -> anyhow::Result<Claims> {
+    let claims = if is_opaque_token(token) {
+        resolve_opaque_token(app, token)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Unknown or expired access token"))?
+    } else {
+        verify_jwt(app, token)?
+    };
+    if is_access_token_denied(app, &claims.jti).await? {
+        return Err(anyhow::anyhow!("Token has been revoked"));
+    }
+    Ok(claims)
+}
+
+// Resolves the scope for a token request against the scopes registered for the client.
+// No requested scope means every registered scope; None if anything requested is not allowed.
+pub fn grant_scopes(requested: Option<&str>, allowed: &[String]) -> Option<String> {
+    let Some(requested) = requested.filter(|s| !s.trim().is_empty()) else {
+        return Some(allowed.join(" "));
+    };
+
+    let scopes: Vec<&str> = requested.split_whitespace().collect();
+    if scopes.iter().all(|s| allowed.iter().any(|a| a == s)) {
+        Some(scopes.join(" "))
+    } else {
+        None
+    }
 }
@@ -1,5 +1,8 @@
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
 use crate::repositories::users::{create_user, get_by_email};
-use crate::services::cache::store_cookie;
+use crate::services::cache::{get_cookie, store_cookie};
 use crate::services::password::{hash_password, verify_hash};
 use crate::state::AppState;
 
@@ -31,8 +34,32 @@ pub async fn authenticate_user(
     }
 }
 
+// What the session cookie points at in redis
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub user_id: String,
+    pub authenticated_at: i64, // unix seconds
+}
+
 pub async fn handle_cookie(app: &AppState, value: &str) -> anyhow::Result<String> {
     let session_id = uuid::Uuid::new_v4().to_string();
-    store_cookie(app, &session_id, value).await?;
+    let session = Session {
+        user_id: value.to_string(),
+        authenticated_at: OffsetDateTime::now_utc().unix_timestamp(),
+    };
+    store_cookie(
+        app,
+        &session_id,
+        &serde_json::to_string(&session)?,
+        app.lifetimes().session,
+    )
+    .await?;
     Ok(session_id)
 }
+
+// None once the session expired, or for sessions stored before they recorded the login time
+pub async fn get_session(app: &AppState, session_id: &str) -> anyhow::Result<Option<Session>> {
+    Ok(get_cookie(app, session_id)
+        .await?
+        .and_then(|serialized| serde_json::from_str(&serialized).ok()))
+}
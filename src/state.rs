@@ -1,10 +1,17 @@
 use core::fmt;
+use jsonwebtoken::Algorithm;
 use redis::Client;
+use ring::aead::LessSafeKey;
+use rustls::server::danger::ClientCertVerifier;
 use sqlx::{mysql::MySqlPoolOptions, MySqlPool};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
+use crate::services::keys::{parse_signing_alg, signing_key_encryption, KeyStore};
+use crate::services::lifetimes::Lifetimes;
+use crate::tls::client_ca_verifier;
+
 #[derive(Clone, Debug)]
 pub struct AppState {
     pub start_time: Instant,
@@ -12,6 +19,14 @@ pub struct AppState {
     pub total_bytes: Arc<AtomicU64>,
     pub max_body_bytes: usize,
     pub max_concurrent_requests: usize,
+    base_url: String,
+    issuer: String,
+    signing_alg: Algorithm,
+    key_rotation_secs: u64,
+    lifetimes: Lifetimes,
+    signing_keys: Arc<RwLock<KeyStore>>,
+    signing_key_encryption: Option<Arc<LessSafeKey>>,
+    client_ca_verifier: Option<Arc<dyn ClientCertVerifier>>,
     pool: MySqlPool,
     redis_client: Client,
 }
@@ -43,12 +58,57 @@ impl AppState {
         let redis_url =
             std::env::var("REDIS_URL").map_err(|_| anyhow::anyhow!("REDIS_URL not set in .env"))?;
         let redis_client = redis::Client::open(redis_url)?;
+
+        // public URL of this server, used for links handed to users and clients
+        let base_url = std::env::var("BASE_URL")
+            .unwrap_or_else(|_| "http://localhost:3000".to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        // `iss` of issued tokens, resource servers compare it verbatim
+        let issuer = std::env::var("ISSUER_URL").unwrap_or_else(|_| base_url.clone());
+
+        // algorithm used for newly issued access tokens
+        let signing_alg = parse_signing_alg(
+            std::env::var("TOKEN_SIGNING_ALG")
+                .as_deref()
+                .unwrap_or("RS256"),
+        )?;
+
+        // how long a signing key stays active before it is rotated out
+        let key_rotation_days: u64 = std::env::var("SIGNING_KEY_ROTATION_DAYS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse()
+            .map_err(|_| anyhow::anyhow!("SIGNING_KEY_ROTATION_DAYS must be a number of days"))?;
+
+        // encrypts private signing keys at rest, without it they are stored as plain DER
+        let signing_key_encryption = std::env::var("SIGNING_KEY_ENCRYPTION_KEY")
+            .ok()
+            .map(|key| signing_key_encryption(&key).map(Arc::new))
+            .transpose()?;
+
+        // CAs that issue client certificates for tls_client_auth (only used when serving TLS)
+        let client_ca_verifier = std::env::var("TLS_CLIENT_CA_PATH")
+            .ok()
+            .map(|path| client_ca_verifier(&path))
+            .transpose()?;
+
+        // default token, code and session lifetimes, clients may override them
+        let lifetimes = Lifetimes::from_env()?;
         Ok(AppState {
             start_time: Instant::now(),
             total_requests: Arc::new(AtomicU64::new(0)),
             total_bytes: Arc::new(AtomicU64::new(0)),
             max_body_bytes,
             max_concurrent_requests,
+            base_url,
+            issuer,
+            signing_alg,
+            key_rotation_secs: key_rotation_days * 24 * 60 * 60,
+            lifetimes,
+            signing_keys: Arc::new(RwLock::new(KeyStore::default())),
+            signing_key_encryption,
+            client_ca_verifier,
             pool,
             redis_client,
         })
@@ -62,6 +122,38 @@ impl AppState {
         &self.redis_client
     }
 
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn signing_alg(&self) -> Algorithm {
+        self.signing_alg
+    }
+
+    pub fn key_rotation_secs(&self) -> u64 {
+        self.key_rotation_secs
+    }
+
+    pub fn lifetimes(&self) -> &Lifetimes {
+        &self.lifetimes
+    }
+
+    pub fn signing_keys(&self) -> &RwLock<KeyStore> {
+        &self.signing_keys
+    }
+
+    pub fn signing_key_encryption(&self) -> Option<&LessSafeKey> {
+        self.signing_key_encryption.as_deref()
+    }
+
+    pub fn client_ca_verifier(&self) -> Option<&dyn ClientCertVerifier> {
+        self.client_ca_verifier.as_deref()
+    }
+
     pub fn increment_requests(&self) {
         self.total_requests.fetch_add(1, Ordering::Relaxed);
     }